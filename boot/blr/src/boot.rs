@@ -7,8 +7,6 @@ mod arch;
 use common::{elf::*, *};
 use arch::*;
 
-pub const KERNEL_FILE: &str = "/sys/aris";
-
 pub const ROOT_FILES: [&str; 3] = [
     KERNEL_FILE,
     "/sys/drivers/libtest1.so",
@@ -5,7 +5,7 @@ mod loader;
 mod logger;
 mod display;
 
-use common::{ArrayTable, BootInfo, MemType, MemoryDesc, MemoryRegion, FileDescriptor, MAX_DESCRIPTORS, PAGE_SIZE};
+use common::{ArrayTable, BootInfo, MemType, MemoryDesc, MemoryRegion, FileDescriptor, KERNEL_FILE, MAX_CMDLINE, MAX_DESCRIPTORS, PAGE_SIZE};
 use uefi::{mem::memory_map::MemoryMap, prelude::*};
 use uefi::boot::{MemoryAttribute, MemoryType};
 use log::{info, debug};
@@ -15,8 +15,8 @@ use core::mem;
 use core::panic::PanicInfo;
 
 use core::alloc::Layout;
-use uefi::{Identify, proto::media::fs::SimpleFileSystem};
-use blr::{KERNEL_FILE, ROOT_FILES, load_kernel, jump_to_kernel};
+use uefi::{Identify, proto::loaded_image::LoadedImage, proto::media::fs::SimpleFileSystem};
+use blr::{ROOT_FILES, load_kernel, jump_to_kernel};
 
 extern crate alloc;
 
@@ -113,6 +113,26 @@ fn get_rsdp() -> usize {
     rsdp.unwrap() 
 }
 
+// Load options we were started with (From the boot entry or the shell) are passed on as the kernel command line
+fn get_cmdline() -> ([u8; MAX_CMDLINE], usize) {
+    let mut cmdline = [0; MAX_CMDLINE];
+    let image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle()).expect("Could not open loaded image protocol");
+
+    let options = match image.load_options_as_cstr16() {
+        Ok(options) => options,
+        Err(_) => return (cmdline, 0)
+    };
+
+    // Kernel only takes ASCII
+    let mut len = 0;
+    for ch in options.iter().map(|&ch| char::from(ch)).filter(|ch| ch.is_ascii()).take(MAX_CMDLINE) {
+        cmdline[len] = ch as u8;
+        len += 1;
+    }
+
+    info!("Kernel command line: {}", options);
+    (cmdline, len)
+}
 
 #[entry]
 fn main() -> Status {
@@ -135,11 +155,13 @@ fn main() -> Status {
 
     info!("Fetching GPU and memmap info before transferring control to aris");
     let fb_info = display::get_primary_gpu_framebuffer();
+    let (cmdline, cmdline_len) = get_cmdline();
     let mem_info = setup_memory_map();
     let fs_info = ArrayTable {start: file_table.descriptors.as_ptr() as usize, 
         size: size_of::<FileDescriptor>() * file_table.length, entry_size: size_of::<FileDescriptor>()};
 
     let boot_info = BootInfo {kernel_desc: kern_info, framebuffer_desc: fb_info, memory_map_desc: mem_info, init_fs: fs_info,
+        cmdline, cmdline_len,
 #[cfg(feature = "acpi")]
         rsdp
     };
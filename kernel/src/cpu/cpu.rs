@@ -313,6 +313,12 @@ pub fn get_panic_base() -> usize {
     cpu_list.panic_base
 }
 
+// Doesn't take the cpu lock, since the lock validator can get here with it held
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+pub fn get_panic_base_unlocked() -> usize {
+    unsafe { CPU_LIST.local().as_ref() }.panic_base
+}
+
 pub fn set_panic_base(base: usize) {
     let mut cpu_list = CPU_LIST.local().lock();
 
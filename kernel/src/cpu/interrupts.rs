@@ -1,6 +1,10 @@
 use alloc::collections::BTreeMap;
 use crate::Spinlock;
+use crate::sync::RwSpinlock;
 use crate::hal::{disable_interrupts, enable_interrupts, register_interrupt_handler};
+use super::{MAX_CPUS, PerCpu};
+use crate::sched::{Work, WorkList};
+use core::mem::replace;
 use kernel_intf::debug;
 
 // TODO: Have ability to chain interrupts
@@ -14,11 +18,16 @@ struct InterruptHandlerBlock {
     handlers: BTreeMap<usize, InterruptDescriptor>
 }
 
-static INTERRUPT_HANDLERS: Spinlock<InterruptHandlerBlock> = Spinlock::new(InterruptHandlerBlock{handlers: BTreeMap::new()});
+// Read on every interrupt, on every core. Written only when a handler is installed
+static INTERRUPT_HANDLERS: RwSpinlock<InterruptHandlerBlock> = RwSpinlock::new(InterruptHandlerBlock{handlers: BTreeMap::new()});
 
 pub fn general_interrupt_handler(vector: usize) {
-    if let Some(desc) = INTERRUPT_HANDLERS.lock().handlers.get(&vector) {
-        (desc.handler)(vector);
+    // Handler runs with the lock let go. A handler that faults (Or installs a handler) would otherwise take it again
+    // on top of our read, which deadlocks once a writer is waiting
+    let handler = INTERRUPT_HANDLERS.read().handlers.get(&vector).map(|desc| desc.handler);
+
+    if let Some(handler) = handler {
+        handler(vector);
     }      
     else {
         debug!("Spurious interrupt detected at vector: {}", vector);
@@ -29,6 +38,27 @@ pub fn install_interrupt_handler(irq: usize, handler: fn(usize), active_high: bo
     let int_stat = disable_interrupts();
     let vector = register_interrupt_handler(irq, active_high, is_edge_triggered);
 
-    INTERRUPT_HANDLERS.lock().handlers.insert(vector, InterruptDescriptor {irq, handler});
+    INTERRUPT_HANDLERS.write().handlers.insert(vector, InterruptDescriptor {irq, handler});
     enable_interrupts(int_stat);
-}
\ No newline at end of file
+}
+
+// Bottom halves of interrupt handlers. A DPC runs on the same core once the interrupt is acknowledged
+// Interrupts are still disabled at that point, so it must not block or allocate (use sched::queue_work for that)
+static DPC_QUEUES: PerCpu<Spinlock<WorkList>> = PerCpu::new_with(
+    [const {Spinlock::new(WorkList::new())}; MAX_CPUS]
+);
+
+// Returns false if the DPC was already queued, in which case it's going to run once for both
+pub fn queue_dpc(dpc: &'static Work) -> bool {
+    DPC_QUEUES.local().lock().push(dpc)
+}
+
+// Called by the interrupt dispatcher after EOI, only when it's about to leave the outermost handler
+// DPCs queued by a nested handler wait for the one it interrupted
+pub fn run_dpcs() {
+    let mut dpcs = replace(&mut *DPC_QUEUES.local().lock(), WorkList::new());
+
+    while let Some(dpc) = dpcs.pop() {
+        dpc.run();
+    }
+}
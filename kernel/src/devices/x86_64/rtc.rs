@@ -8,7 +8,7 @@ const CMOS_DATA: u16 = 0x71;
 // CMOS uses an index/data port pair (0x70/0x71). All accesses must be
 // serialised so that a register-select on one core can't interleave with
 // a data read from another core.
-static RTC_LOCK: Spinlock<()> = Spinlock::new(());
+static RTC_LOCK: Spinlock<()> = Spinlock::new_with_class((), "RTC_LOCK");
 
 const RTC_SECONDS: u8 = 0x00;
 const RTC_MINUTES: u8 = 0x02;
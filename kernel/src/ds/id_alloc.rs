@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+use kernel_intf::KError;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+// Hands out the lowest free id, so that the id space stays dense
+// Freed ids are held back for a grace period before they're handed out again, so that a stale id
+// (Someone still holding on to the id of an object that just went away) doesn't immediately refer to a new object
+// Grace period is counted in ids freed since, not in time. That's monotonic no matter what state the clock is in
+pub struct IdAllocator {
+    // Bit set => id in use
+    bitmap: Vec<u64>,
+    // Bit set => id freed, but still waiting out it's grace period. Always as long as bitmap
+    held: Vec<u64>,
+    // Freed ids that are still held back, oldest first. Never holds more than grace_period ids
+    released: VecDeque<usize>,
+    max_ids: usize,
+    grace_period: usize
+}
+
+impl IdAllocator {
+    // Ids go from 0 to max_ids - 1. A freed id is held back till grace_period more ids have been freed after it
+    pub const fn new(max_ids: usize, grace_period: usize) -> Self {
+        Self {
+            bitmap: Vec::new(),
+            held: Vec::new(),
+            released: VecDeque::new(),
+            max_ids,
+            grace_period
+        }
+    }
+
+    pub fn alloc(&mut self) -> Result<usize, KError> {
+        // Room for the whole grace period is reserved up front, so that free never has to allocate
+        // Every id that can be freed went through here first
+        if self.released.capacity() < self.grace_period {
+            self.released.try_reserve_exact(self.grace_period - self.released.len()).map_err(|_| KError::OutOfMemory)?;
+        }
+
+        if let Some(id) = self.find_free() {
+            self.set_bit(id)?;
+            return Ok(id);
+        }
+
+        // Running out of ids is worse than reusing one early, so fall back to the one that was freed the longest time ago
+        let id = self.released.pop_front().ok_or(KError::LimitExceeded)?;
+        Self::clear(&mut self.held, id);
+        Self::set(&mut self.bitmap, id);
+        Ok(id)
+    }
+
+    // Returns false if the id isn't allocated, i.e. it was never handed out or it's already been freed
+    pub fn free(&mut self, id: usize) -> bool {
+        if !self.is_allocated(id) {
+            return false;
+        }
+
+        Self::clear(&mut self.bitmap, id);
+        if self.grace_period == 0 {
+            return true;
+        }
+
+        // Oldest one has waited long enough once grace_period ids have been freed after it
+        if self.released.len() == self.grace_period {
+            let oldest = self.released.pop_front().unwrap();
+            Self::clear(&mut self.held, oldest);
+        }
+
+        // Within the capacity reserved by alloc
+        self.released.push_back(id);
+        Self::set(&mut self.held, id);
+        true
+    }
+
+    pub fn is_allocated(&self, id: usize) -> bool {
+        self.bitmap.get(id / BITS_PER_WORD).is_some_and(|word| word & (1 << (id % BITS_PER_WORD)) != 0)
+    }
+
+    // Only limits future allocations. Ids above the new maximum that are already out stay valid
+    pub fn set_max_ids(&mut self, max_ids: usize) {
+        self.max_ids = max_ids;
+    }
+
+    pub fn get_max_ids(&self) -> usize {
+        self.max_ids
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        let mut words = self.bitmap.iter().zip(self.held.iter()).map(|(used, held)| used | held);
+        let id = words.position(|word| word != u64::MAX).map_or(
+            self.bitmap.len() * BITS_PER_WORD,
+            |word_idx| word_idx * BITS_PER_WORD + (self.bitmap[word_idx] | self.held[word_idx]).trailing_ones() as usize
+        );
+
+        (id < self.max_ids).then_some(id)
+    }
+
+    fn set_bit(&mut self, id: usize) -> Result<(), KError> {
+        let word_idx = id / BITS_PER_WORD;
+        if word_idx >= self.bitmap.len() {
+            let additional = word_idx + 1 - self.bitmap.len();
+            self.bitmap.try_reserve(additional).map_err(|_| KError::OutOfMemory)?;
+            self.held.try_reserve(additional).map_err(|_| KError::OutOfMemory)?;
+            self.bitmap.resize(word_idx + 1, 0);
+            self.held.resize(word_idx + 1, 0);
+        }
+
+        Self::set(&mut self.bitmap, id);
+        Ok(())
+    }
+
+    // Both only touch words that already exist
+    fn set(bits: &mut [u64], id: usize) {
+        bits[id / BITS_PER_WORD] |= 1 << (id % BITS_PER_WORD);
+    }
+
+    fn clear(bits: &mut [u64], id: usize) {
+        bits[id / BITS_PER_WORD] &= !(1 << (id % BITS_PER_WORD));
+    }
+}
@@ -213,6 +213,28 @@ impl<T, A: Allocator<ListNode<T>>> List<T, A> {
         self.insert_node(this, false);
     }
 
+    // This is unsafe, since it is caller's responsibility to ensure that next is a valid node that is part of this list
+    pub unsafe fn insert_node_before(&mut self, this: NonNull<ListNode<T>>, next: NonNull<ListNode<T>>) {
+        if self.head == Some(next) {
+            self.insert_node_at_head(this);
+            return;
+        }
+
+        unsafe {
+            let this_node = &mut *this.as_ptr();
+            let next_node = &mut *next.as_ptr();
+            let prev_node = &mut *next_node.prev.as_ptr();
+
+            this_node.prev = next_node.prev;
+            this_node.next = next;
+
+            prev_node.next = this;
+            next_node.prev = this;
+        }
+
+        self.num_nodes += 1;
+    }
+
     pub fn pop_node(&mut self) {
         if let Some(node) = self.tail {
             unsafe {
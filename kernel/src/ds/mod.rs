@@ -2,4 +2,7 @@ mod list;
 pub use list::*;
 
 mod queue;
-pub use queue::*;
\ No newline at end of file
+pub use queue::*;
+
+mod id_alloc;
+pub use id_alloc::*;
\ No newline at end of file
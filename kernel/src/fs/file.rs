@@ -7,7 +7,7 @@ use common::{MemoryRegion, PAGE_SIZE};
 use kernel_intf::{KError, info};
 use crate::INIT_FS;
 use crate::hal::copy_user_memory;
-use crate::mem::{PageDescriptor, PoolAllocatorGlobal, allocate_memory, deallocate_memory};
+use crate::mem::{PageDescriptor, PoolAllocatorGlobal, allocate_memory, copy_to_user, deallocate_memory};
 use crate::sched::{add_new_handle, Handle::FileHandle};
 use crate::sync::Spinlock;
 
@@ -95,11 +95,11 @@ impl FileBuffer {
 
         if self.is_user {
             unsafe {
-                copy_user_memory(
+                copy_to_user(
                     (self.region.base_address as *mut u8).add(offset),
                     from as *const u8, 
                     len
-                );
+                ).expect("Failed to resolve copy-on-write block of user buffer!");
             }
         }
         else {
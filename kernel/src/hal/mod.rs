@@ -17,6 +17,12 @@ pub fn sleep() -> ! {
     loop{}    
 }
 
+#[cfg(test)]
+pub fn wait_for_interrupt() {}
+
+#[cfg(test)]
+pub fn wait_for_interrupt_with_hint(_: usize, _: u32) {}
+
 #[cfg(test)]
 #[inline(always)]
 pub fn yield_cpu() {}
@@ -27,11 +27,14 @@ pub struct CPUFeatures {
     pub tsc_invariant: bool,
     pub x2apic: bool,
     pub pat: bool,
+    pub mwait: bool,
 
-    pub phy_addr_width: u8
+    pub phy_addr_width: u8,
+    // CPUID leaf 5 EDX. Number of MWAIT sub C-states for every C-state, 4 bits each
+    pub mwait_substates: u32
 }
 
-const FEATURE_MAP: [FeatureDescriptor; 13] = [
+const FEATURE_MAP: [FeatureDescriptor; 14] = [
     FeatureDescriptor {
         fn_num: 0x1,
         ext_fn_num: 0,
@@ -139,6 +142,15 @@ const FEATURE_MAP: [FeatureDescriptor; 13] = [
         is_required: FeatureState::NotRequired(|val| {
             val.pat = true;
         })
+    },
+    FeatureDescriptor {
+        fn_num: 0x1,
+        ext_fn_num: 0,
+        reg_idx: 2,
+        bit_idx: 3,
+        is_required: FeatureState::NotRequired(|val| {
+            val.mwait = true;
+        })
     }
 ];
 
@@ -177,6 +189,10 @@ pub fn init() {
         }
 
         inst.phy_addr_width = (cpuid(0x80000008,0)[0] & 0xff) as u8;
+        if inst.mwait {
+            inst.mwait_substates = cpuid(0x5, 0)[3];
+        }
+
         info!("CPU max physical address width = {}", inst.phy_addr_width);
         Spinlock::new(
             inst
@@ -184,4 +200,17 @@ pub fn init() {
     });
 
     debug!("Features = {:?}", *CPU_FEATURES.get().unwrap().lock());
+}
+
+// MWAIT hint for entering the given C-state (1 => C1 and so on)
+// None if the cpu doesn't do MWAIT or doesn't have that C-state
+pub fn get_mwait_hint(cstate: u8) -> Option<u32> {
+    let features = *CPU_FEATURES.get()?.lock();
+    if !features.mwait || cstate == 0 || cstate > 7 {
+        return None;
+    }
+
+    // C0 is counted in the substate field too, so C1 sits at index 1
+    let substates = (features.mwait_substates >> (4 * cstate as u32)) & 0xf;
+    (substates != 0).then_some((cstate as u32 - 1) << 4)
 }
\ No newline at end of file
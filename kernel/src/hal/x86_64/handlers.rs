@@ -3,14 +3,16 @@ use kernel_intf::{debug, info};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::ptr::NonNull;
 use crate::cpu::{self, MAX_CPUS, PerCpu, general_interrupt_handler};
-use crate::hal::{enable_scheduler_timer, get_core, get_per_cpu_base, get_per_cpu_kernel_base};
+use crate::hal::{disable_interrupts, enable_interrupts, enable_scheduler_timer, get_core, get_per_cpu_base, get_per_cpu_kernel_base, set_per_cpu_base};
 use crate::infra;
 use crate::sync::Spinlock;
-use super::{lapic, timer};
+use super::lapic;
 use crate::mem::on_page_fault;
 use super::lapic::{eoi, get_error};
 use super::cpu::get_bsp_lapic_id;
 use super::MAX_INTERRUPT_VECTORS;
+use super::{USER_CS, USER_SS, UserContext};
+use crate::sched::{prepare_user_return, toggle_cur_task_kernel_mode};
 use super::asm;
 use crate::hal::halt;
 use crate::devices::ioapic::add_redirection_entry;
@@ -130,11 +132,57 @@ impl CPUContext {
             rdx: 0, rcx: 0, rbx: 0, rax: 0, vector: 0, rip: 0, cs: 0, rflags: 0, rsp: 0, ss: 0 
         }
     }
+
+    fn is_user_mode(&self) -> bool {
+        self.cs & 0x3 == 0x3
+    }
+
+    // RFLAGS register bit 9 is IF
+    fn had_interrupts_enabled(&self) -> bool {
+        self.rflags & (1 << 9) != 0
+    }
+
+    fn to_user_context(&self) -> UserContext {
+        UserContext {
+            rax: self.rax, rbx: self.rbx, rcx: self.rcx, rdx: self.rdx, rsi: self.rsi, rdi: self.rdi, rbp: self.rbp, 
+            r8: self.r8, r9: self.r9, r10: self.r10, r11: self.r11, r12: self.r12, r13: self.r13, r14: self.r14, 
+            r15: self.r15, rip: self.rip, rflags: self.rflags, rsp: self.rsp
+        }
+    }
+
+    fn apply_user_context(&mut self, context: &UserContext) {
+        self.rax = context.rax;
+        self.rbx = context.rbx;
+        self.rcx = context.rcx;
+        self.rdx = context.rdx;
+        self.rsi = context.rsi;
+        self.rdi = context.rdi;
+        self.rbp = context.rbp;
+        self.r8 = context.r8;
+        self.r9 = context.r9;
+        self.r10 = context.r10;
+        self.r11 = context.r11;
+        self.r12 = context.r12;
+        self.r13 = context.r13;
+        self.r14 = context.r14;
+        self.r15 = context.r15;
+        self.rip = context.rip;
+        self.rflags = context.rflags;
+        self.rsp = context.rsp;
+        self.cs = USER_CS;
+        self.ss = USER_SS;
+    }
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn global_interrupt_handler(vector: u64, cpu_context: *const CPUContext) -> *const CPUContext {
-    PER_CPU_GLOBAL_CONTEXT.local().store(cpu_context.addr(), Ordering::Release);
+    // Interrupt gates keep interrupts out of a handler, but an exception (Say a page fault) can still nest on top of it
+    // Whatever interrupted code had interrupts enabled can't be a handler, so this is the outermost one
+    let outermost = unsafe {
+        (*cpu_context).is_user_mode() || (*cpu_context).had_interrupts_enabled()
+    };
+
+    let outer_context = PER_CPU_GLOBAL_CONTEXT.local().swap(cpu_context.addr(), Ordering::AcqRel);
     unsafe {
         VECTOR_TABLE[vector as usize](vector as usize);
     }
@@ -142,8 +190,53 @@ extern "C" fn global_interrupt_handler(vector: u64, cpu_context: *const CPUConte
     if vector as usize > DEBUG_VECTOR && vector as usize != SYS_VECTOR {
         eoi();
     }
+
+    if !outermost {
+        // Hand the context back to the handler we nested in, unless this one switched tasks
+        let context = PER_CPU_GLOBAL_CONTEXT.local().load(Ordering::Acquire);
+        if context == cpu_context.addr() {
+            PER_CPU_GLOBAL_CONTEXT.local().store(outer_context, Ordering::Release);
+        }
+
+        return context as *const CPUContext;
+    }
+
+    cpu::run_dpcs();
+
+    // Pending signals are delivered on the way back to user mode, as long as we're still on the interrupted thread
+    // A thread that got switched in here has them delivered on it's next way out
+    let context = PER_CPU_GLOBAL_CONTEXT.local().load(Ordering::Acquire) as *mut CPUContext;
+    unsafe {
+        if context.addr() == cpu_context.addr() && (*context).is_user_mode() {
+            prepare_interrupted_user_return(context);
+        }
+    }
     
-    PER_CPU_GLOBAL_CONTEXT.local().load(Ordering::Acquire) as *const CPUContext
+    context as *const CPUContext
+}
+
+// Runs prepare_user_return for a thread that was interrupted in user mode, the same way the syscall path does
+// Nothing is held at this point, so interrupts can go back on. Until they're off again, kernel gs base has to be
+// the same as the active one, since a nested interrupt swaps them
+unsafe fn prepare_interrupted_user_return(context: *mut CPUContext) {
+    let user_gs = get_per_cpu_base();
+    set_per_cpu_base(get_per_cpu_kernel_base());
+    toggle_cur_task_kernel_mode();
+    enable_interrupts(true);
+
+    unsafe {
+        let mut user_context = (*context).to_user_context();
+        if prepare_user_return(&mut user_context) {
+            (*context).apply_user_context(&user_context);
+        }
+    }
+
+    disable_interrupts();
+    toggle_cur_task_kernel_mode();
+    set_per_cpu_base(user_gs);
+
+    // Interrupts taken in the meantime replaced it, and we might not even be on the same core anymore
+    PER_CPU_GLOBAL_CONTEXT.local().store(context.addr(), Ordering::Release);
 }
 
 fn default_handler(idx: usize) {
@@ -214,14 +307,12 @@ fn debug_handler(_vector: usize) {
 
 // It's fine to handle these without locks since CPU won't interrupt during this call
 // This is true since we are already in interrupt handler and further interrupts are masked by current design
+// The timer is one-shot. Scheduler re-arms it for the next tick it actually needs
 fn timer_handler(_vector: usize) {
     crate::sched::schedule();
-
-    // Reload the timer
-    lapic::setup_timer_value(timer::BASE_COUNT.local().load(Ordering::Acquire) as u32);
 }
 
-// Do the same thing as timer handler, except we don't reload the timer register and we won't send EOI
+// Do the same thing as timer handler, except we won't send EOI
 fn yield_handler(_vector: usize) {
     crate::sched::schedule();
 }
@@ -238,10 +329,11 @@ fn sys_handler(_vector: usize) {
 
 fn page_fault_handler(_vector: usize) {
     let fault_address = asm::read_cr2();
+    let context = unsafe {*(fetch_context() as *const CPUContext)};
     
-    info!("{:?}", unsafe {*(fetch_context() as *const CPUContext)});
+    info!("{:?}", context);
 
-    on_page_fault(fault_address as usize);
+    on_page_fault(fault_address as usize, context.is_user_mode());
 }
 
 pub fn fetch_context() -> usize {
@@ -252,6 +344,19 @@ pub fn switch_context(new_context: usize) {
     PER_CPU_GLOBAL_CONTEXT.local().store(new_context, Ordering::Release);
 }
 
+// Leaves the kernel through the interrupt return path, which restores every register
+// Caller must have interrupts disabled and user gs in place, just like before sysret
+pub fn resume_user_context(user_context: &UserContext) -> ! {
+    let mut context = CPUContext::new();
+    context.apply_user_context(user_context);
+
+    unsafe {
+        asm::restore_context(&context as *const CPUContext as u64);
+    }
+
+    panic!("Returned from restore_context!!");
+}
+
 pub fn create_kernel_context(handler: fn() -> !, stack_base: *mut u8) -> usize {
     let mut sp = stack_base as usize;
 
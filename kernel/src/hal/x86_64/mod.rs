@@ -55,6 +55,12 @@ pub fn enable_interrupts(int_status: bool) {
     }
 }
 
+#[cfg(not(test))]
+pub fn are_interrupts_enabled() -> bool {
+    // RFLAGS register bit 9 is IF
+    asm::read_rflags() & (1 << 9) != 0
+}
+
 #[cfg(test)]
 pub fn disable_interrupts() -> bool {
     true
@@ -64,8 +70,14 @@ pub fn disable_interrupts() -> bool {
 pub fn enable_interrupts(_: bool) {
 }
 
+#[cfg(test)]
+pub fn are_interrupts_enabled() -> bool {
+    true
+}
+
 pub use asm::read_port_u8;
 pub use asm::write_port_u8;
+pub use features::get_mwait_hint;
 
 pub struct Spinlock {
     state: AtomicU64
@@ -124,6 +136,44 @@ pub fn sleep() -> ! {
     }
 }
 
+// Returns once an interrupt has been taken. Interrupts are left enabled
+#[cfg(not(test))]
+#[inline(always)]
+pub fn wait_for_interrupt() {
+    unsafe {
+        core::arch::asm!(
+            "sti",
+            "hlt",
+            options(nostack)
+        );
+    }
+}
+
+// Same as wait_for_interrupt, but lets the core drop into the C-state given by hint (See get_mwait_hint)
+// A write to the cache line at monitor_addr wakes the core up as well
+#[cfg(not(test))]
+#[inline(always)]
+pub fn wait_for_interrupt_with_hint(monitor_addr: usize, hint: u32) {
+    unsafe {
+        core::arch::asm!(
+            "monitor",
+            in("rax") monitor_addr,
+            in("ecx") 0,
+            in("edx") 0,
+            options(nostack)
+        );
+
+        // sti only takes effect after the next instruction, so an interrupt can't slip in before mwait
+        core::arch::asm!(
+            "sti",
+            "mwait",
+            in("eax") hint,
+            in("ecx") 0,
+            options(nostack)
+        );
+    }
+}
+
 #[cfg(not(test))]
 #[inline(always)]
 pub fn yield_cpu() {
@@ -218,4 +268,15 @@ pub fn disable_scheduler_timer() {
     lapic::disable_timer();
 }
 
+// One-shot scheduler interrupt after the given number of ticks (QUANTUM ms each)
+pub fn enable_scheduler_timer_for(ticks: usize) {
+    let count = timer::BASE_COUNT.local().load(Ordering::Acquire).saturating_mul(ticks).min(u32::MAX as usize);
+    lapic::enable_timer(count as u32);
+}
+
+// Monotonic time since boot in milliseconds
+pub fn get_time_ms() -> usize {
+    read_timestamp() / timer::TSC_TICKS_PER_MS.load(Ordering::Relaxed).max(1)
+}
+
 
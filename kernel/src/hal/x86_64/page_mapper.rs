@@ -203,6 +203,7 @@ impl PageMapper {
         let is_user = flags & mem::PageDescriptor::USER != 0;
         let mut is_mmio = flags & mem::PageDescriptor::MMIO != 0;
         let mut is_wc   = flags & mem::PageDescriptor::WC   != 0;
+        let is_cow = flags & mem::PageDescriptor::COW != 0;
         let is_global_feature = CPU_FEATURES.get().unwrap().lock().pge;
         let is_pat_feature = CPU_FEATURES.get().unwrap().lock().pat;
 
@@ -327,7 +328,7 @@ impl PageMapper {
                             is_global_feature,
                             PTE::G
                         )
-                        | PTE::RW
+                        | en_flag!(!is_cow, PTE::RW)
                         | PTE::P
                 );
             }
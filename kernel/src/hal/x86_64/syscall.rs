@@ -1,8 +1,9 @@
 use kernel_intf::debug;
 use crate::hal::{disable_interrupts, enable_interrupts, get_per_cpu_base, get_per_cpu_kernel_base, set_per_cpu_base, set_tss_stack};
 use crate::hal::x86_64::cpu_regs::INIT_RFLAGS;
-use crate::sched::{get_current_task, syscall_dispatcher, toggle_cur_task_kernel_mode};
+use crate::sched::{get_current_task, prepare_user_return, syscall_dispatcher, toggle_cur_task_kernel_mode};
 use crate::hal::x86_64::asm;
+use super::{is_user_range, resume_user_context};
 
 const STAR: u32 = 0xc000_0081;
 const LSTAR: u32 = 0xc000_0082;
@@ -19,12 +20,89 @@ unsafe extern "C" {
 
 pub const MAX_ARCH_ARGS: usize = 6;
 
+// Selectors that sysret loads (Based on STAR)
+pub const USER_CS: u64 = 0x23;
+pub const USER_SS: u64 = 0x1b;
+
+// Flags that user code is allowed to control (CF, PF, AF, ZF, SF, DF, OF)
+const USER_RFLAGS_MASK: u64 = 0xcd5;
+
 #[repr(C)]
 struct SyscallContext {
     pad: u64,
     syscall_number: u64,
     args: [u64; MAX_ARCH_ARGS],
-    user_gs: u64
+    user_gs: u64,
+    // Rest of the user state pushed by SYSCALL_ENTRY
+    rflags: u64,
+    rip: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    rsp: u64
+}
+
+impl SyscallContext {
+    // State user code would see once sysret is done
+    fn to_user_context(&self, stat: i64) -> UserContext {
+        UserContext {
+            rax: stat as u64, rbx: self.rbx, rcx: self.rip, rdx: self.args[2], rsi: self.args[1], rdi: self.args[0], 
+            rbp: self.rbp, r8: self.args[4], r9: self.args[5], r10: self.args[3], r11: self.rflags, r12: self.r12, 
+            r13: self.r13, r14: self.r14, r15: self.r15, rip: self.rip, rflags: self.rflags, rsp: self.rsp
+        }
+    }
+}
+
+// Register state of user code at the point it entered the kernel
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserContext {
+    pub(super) rax: u64,
+    pub(super) rbx: u64,
+    pub(super) rcx: u64,
+    pub(super) rdx: u64,
+    pub(super) rsi: u64,
+    pub(super) rdi: u64,
+    pub(super) rbp: u64,
+    pub(super) r8: u64,
+    pub(super) r9: u64,
+    pub(super) r10: u64,
+    pub(super) r11: u64,
+    pub(super) r12: u64,
+    pub(super) r13: u64,
+    pub(super) r14: u64,
+    pub(super) r15: u64,
+    pub(super) rip: u64,
+    pub(super) rflags: u64,
+    pub(super) rsp: u64
+}
+
+impl UserContext {
+    pub fn get_stack_pointer(&self) -> usize {
+        self.rsp as usize
+    }
+
+    // Resume at entry(arg0, arg1) with the given stack. Return address must already be at the top of the stack
+    pub fn set_entry(&mut self, entry: usize, arg0: usize, arg1: usize, stack: usize) {
+        self.rip = entry as u64;
+        self.rdi = arg0 as u64;
+        self.rsi = arg1 as u64;
+        self.rsp = stack as u64;
+    }
+
+    pub fn set_return_value(&mut self, value: i64) {
+        self.rax = value as u64;
+    }
+
+    // Context that comes back from user memory can't be trusted
+    // Returns false if it can't be resumed at all
+    pub fn sanitize(&mut self) -> bool {
+        self.rflags = (self.rflags & USER_RFLAGS_MASK) | unsafe { INIT_RFLAGS };
+        is_user_range(self.rip as usize, 0) && is_user_range(self.rsp as usize, 0)
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -45,7 +123,16 @@ extern "C" fn arch_syscall_handler(context: *mut SyscallContext) -> i64 {
         ((*context).syscall_number, &(*context).args)
     };
 
-    let stat = syscall_dispatcher(syscall_number, args);
+    // Some syscalls (fork) need to know where user code is going to resume
+    let mut user_context = unsafe {
+        (*context).to_user_context(0)
+    };
+
+    let stat = syscall_dispatcher(syscall_number, args, &user_context);
+    user_context.set_return_value(stat);
+
+    // Signal delivery (or sigreturn) changes where user code resumes
+    let resume_changed = prepare_user_return(&mut user_context);
 
     toggle_cur_task_kernel_mode();
     disable_interrupts();
@@ -55,6 +142,11 @@ extern "C" fn arch_syscall_handler(context: *mut SyscallContext) -> i64 {
         set_per_cpu_base((*context).user_gs);
     }
 
+    // sysret can't restore rcx and r11, so take the interrupt return path instead
+    if resume_changed {
+        resume_user_context(&user_context);
+    }
+
     stat
 }
 
@@ -98,4 +190,19 @@ pub fn transfer_control_to_user(user_start_addr: usize, user_stack_base: usize)
     }
     
     panic!("Exec path returned to transfer_control_to_user!!");
+}
+
+// Same as transfer_control_to_user, but every register is loaded from the given context
+pub fn transfer_control_to_user_context(context: &UserContext) -> ! {
+    // This will be reenabled once switched to user land
+    toggle_cur_task_kernel_mode();
+    set_tss_stack(get_current_task()
+        .expect("transfer_control_to_user_context() called in idle task!")
+        .lock()
+        .get_stack()
+        .expect("User thread expected to have non-empty kernel stack!") as u64
+    );
+    disable_interrupts();
+
+    resume_user_context(context);
 }
\ No newline at end of file
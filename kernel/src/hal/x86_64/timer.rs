@@ -8,6 +8,8 @@ use super::lapic;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub static BASE_COUNT: PerCpu<AtomicUsize> = PerCpu::new_with([const {AtomicUsize::new(0)}; MAX_CPUS]);
+// Measured on the BSP. Invariant TSC runs at the same rate on all cores
+pub static TSC_TICKS_PER_MS: AtomicUsize = AtomicUsize::new(0);
 static EXPECTED_VISITOR: AtomicUsize = AtomicUsize::new(0);
 
 // Smallest granularity timer
@@ -54,6 +56,10 @@ pub fn init() {
     let base_freq = num_ticks_passed * 1000;
     
     info!("CPU Base Clock frequency measured as {}Hz", base_freq);
+
+    if core == 0 {
+        TSC_TICKS_PER_MS.store(num_ticks_passed as usize, Ordering::Relaxed);
+    }
     
     // Now measure APIC timer
     lapic::init_timer();
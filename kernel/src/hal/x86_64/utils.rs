@@ -46,6 +46,15 @@ pub fn canonicalize_virtual(addr: usize) -> usize {
     VirtAddr::new(addr).get()
 }
 
+// User space is the lower canonical half, minus it's last page
+// That keeps a user rip / rsp at the very top from running off into non canonical space, where iretq would fault in ring 0
+pub const USER_SPACE_END: usize = 0x7FFF_FFFF_F000;
+
+pub fn is_user_range(addr: usize, len: usize) -> bool {
+    canonicalize_virtual(addr) == addr && addr < USER_SPACE_END
+    && addr.checked_add(len).is_some_and(|end| end <= USER_SPACE_END)
+}
+
 pub unsafe fn copy_user_memory(to: *mut u8, from: *const u8, len: usize) {
     if len == 0 {
         return;
@@ -6,7 +6,7 @@ use core::ffi::c_char;
 use common::StrRef;
 use common::elf::*;
 use rustc_demangle::demangle;
-use crate::{cpu, logger};
+use crate::{cpu, logger, sched};
 use kernel_intf::println;
 use crate::sync::Spinlock;
 use crate::hal::{self, IPIRequestType, notify_core};
@@ -87,6 +87,7 @@ fn common_panic_handler(mod_name: &str, info: PanicCommon) -> ! {
     println!("Kernel panic on core {}!!", core);
     info.print();
     println!("Module: {}", mod_name);
+    print_current_task();
 
     let stack_base = cpu::get_panic_base(); 
     start_unwind(mod_name, stack_base);
@@ -94,6 +95,29 @@ fn common_panic_handler(mod_name: &str, info: PanicCommon) -> ! {
     hal::halt();
 }
 
+// Whoever panicked could be holding the task or process lock, so never wait on them here
+fn print_current_task() {
+    let Some(task) = sched::get_current_task() else {
+        println!("Task: Idle");
+        return;
+    };
+
+    let Some(task) = task.try_lock() else {
+        println!("Task: {}", DEFAULT_PANIC_STRING);
+        return;
+    };
+
+    println!("Task: {} ({})", task.get_id(), task.get_name().unwrap_or(sched::UNNAMED));
+
+    let process = task.get_process();
+    if let Some(process) = process.as_ref().and_then(|process| process.try_lock()) {
+        println!("Process: {} ({})", process.get_id(), process.get_name().unwrap_or(sched::UNNAMED));
+    }
+    else {
+        println!("Process: {}", DEFAULT_PANIC_STRING);
+    }
+}
+
 pub fn start_unwind(mod_name: &str, stack_base: usize) {
     let mut unwind_list: [usize; STACK_UNWIND_DEPTH] = [0; STACK_UNWIND_DEPTH];
 
@@ -108,21 +132,28 @@ pub fn start_unwind(mod_name: &str, stack_base: usize) {
             println!("(Empty) => Current stack base: {:#X}, Current stack top: {:#X}", cur_base, stack_base);
         }
 
-        for addr in start_depth..actual_depth {
-            if unwind_list[addr] != 0 {
-                let sym_info = symbol_trace(unwind_list[addr]);
-                if let Some(sym) = sym_info {
-                    println!("{:#X}({}!{}+{:#X})", unwind_list[addr], sym.0, demangle(sym.1), sym.2);
-                }
-                else {
-                    println!("{:#X}(??)", unwind_list[addr]);
-                }
-            }
+        if start_depth < actual_depth {
+            print_callstack(&unwind_list[start_depth..actual_depth]);
         }
     }
 
 }
 
+// Prints return addresses gathered by hal::unwind_stack, along with their symbols
+pub fn print_callstack(addresses: &[usize]) {
+    for &addr in addresses {
+        if addr != 0 {
+            let sym_info = symbol_trace(addr);
+            if let Some(sym) = sym_info {
+                println!("{:#X}({}!{}+{:#X})", addr, sym.0, demangle(sym.1), sym.2);
+            }
+            else {
+                println!("{:#X}(??)", addr);
+            }
+        }
+    }
+}
+
 fn symbol_trace_do_work(addr: usize, module: &ModuleDescriptor) -> Option<(&'static str, &'static str, usize)> {
     // Check if this symbol is part of this module
     if (addr < module.info.base) || (addr >= module.info.base + module.info.size) {
@@ -32,7 +32,7 @@ use alloc::collections::VecDeque;
 #[cfg(test)]
 mod tests;
 
-use sync::{Once, Spinlock};
+use sync::{KCondvar, KMutex, Once, Spinlock};
 use cpu::install_interrupt_handler;
 use fs::FileBuffer;
 use hal::read_port_u8;
@@ -57,8 +57,6 @@ struct RemapEntry {
     flags: u8
 }
 
-const KERNEL_PATH: &'static str = "/sys/aris";
-
 struct InitFS {
     fs: BTreeMap<&'static str, &'static [u8]>,
     symlinks: BTreeMap<&'static str, &'static str>
@@ -75,16 +73,13 @@ fn clear_keyboard_output_buffer() {
     }
 }
 
-static TASK_COUNTER: Once<KSem> = Once::new();
 static WAIT_EVENT: Once<KSem> = Once::new();
 static WAIT_EVENT2: Once<KSem> = Once::new();
 
 // Checking thread subsystem
 fn task_spawn() -> ! {
     let mut tasks: VecDeque<KThread> = VecDeque::new();
-    TASK_COUNTER.call_once(|| {
-        KSem::new(0, 5)
-    });
+    let task_counter = KSem::new(0, 5);
 
     WAIT_EVENT.call_once(|| {
         KSem::new(0, 1)
@@ -96,10 +91,11 @@ fn task_spawn() -> ! {
 
     for idx in 0..5 {
         info!("Creating task {} in task spawner", idx);
-        tasks.push_back(sched::create_thread(|| {
+        let task_counter = task_counter.clone();
+        tasks.push_back(sched::create_thread_with(move || {
             let id = sched::get_current_task_id().unwrap(); 
             info!("Running task: {}", id);
-            TASK_COUNTER.get().unwrap().signal();
+            task_counter.signal();
 
             info!("id={}", id);
             
@@ -108,13 +104,13 @@ fn task_spawn() -> ! {
             
                 sched::delay_ms(1000);
             }
-        }).unwrap());
+        }, None).unwrap());
     }
 
     info!("Task spawner going to wait!");
     
     for _ in 0..5 {
-        TASK_COUNTER.get().unwrap().wait().unwrap();
+        task_counter.wait().unwrap();
     }
 
     info!("Task spawner starting kill spree");
@@ -153,7 +149,7 @@ fn process_spawn() -> ! {
                         info!("Running thread with id {} with process id {} on core {}", thread_id, proc_id, hal::get_core());
                         sched::delay_ms(1000);
                     }
-                }).expect("Failed to create new thread");
+                }, None).expect("Failed to create new thread");
             }
 
             loop {
@@ -165,7 +161,7 @@ fn process_spawn() -> ! {
                 sched::kill_process(1);
                 sched::exit_process();
             }
-        }, false).expect("Failed to create process");
+        }, false, None).expect("Failed to create process");
     }
 
     // This pattern should be never followed in a real scenario, but this is here just for testing
@@ -181,7 +177,8 @@ fn process_spawn() -> ! {
     hal::halt();
 }
 
-static QUEUE: Spinlock<DynList<[i64; 64]>> = Spinlock::new(List::new());
+static QUEUE: KMutex<DynList<[i64; 64]>> = KMutex::new(List::new());
+static QUEUE_READY: KCondvar = KCondvar::new();
 
 fn thread_creator() -> ! {
     let id = sched::get_current_task_id().unwrap();
@@ -189,9 +186,9 @@ fn thread_creator() -> ! {
     loop {
         sched::delay_ms(1000);
         debug!("Running thread with id {}", id);
-        sched::create_thread(thread_creator).expect("Failed to create child thread!");
+        sched::create_thread(thread_creator, None).expect("Failed to create child thread!");
         if counter % 5 == 0 {
-            sched::create_process(thread_creator, false).expect("Failed to create child process!");
+            sched::create_process(thread_creator, false, None).expect("Failed to create child process!");
         }
         
         if counter % 10 == 0 {
@@ -203,6 +200,10 @@ fn thread_creator() -> ! {
 
 fn kern_main() -> ! {
     info!("Starting main kernel init");
+
+    // Every core is up by now
+    #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    sync::lockdep::init();
     
     KEYBOARD_EVENT.call_once(|| {
         KSem::new(0, 1)
@@ -212,6 +213,11 @@ fn kern_main() -> ! {
     clear_keyboard_output_buffer();
     install_interrupt_handler(1, key_notifier, true, true);
 
+    let idle_strategy = parse_idle_strategy(BOOT_INFO.get().unwrap().get_cmdline());
+    if sched::set_idle_strategy(idle_strategy).is_err() {
+        info!("Idle strategy {:?} not supported by cpu, using halt instead", idle_strategy);
+    }
+
     sched::init();
     loader::init();
 
@@ -229,7 +235,7 @@ fn kern_main() -> ! {
             .expect("Failed to load driver in different thread!");
 
             sched::exit_thread();
-        }).unwrap();
+        }, None).unwrap();
 
         sched::create_process(|| {
             info!("Loading driver from different process");
@@ -239,7 +245,7 @@ fn kern_main() -> ! {
             sched::delay_ms(1000);
 
             sched::exit_process(); 
-        }, false).unwrap();
+        }, false, None).unwrap();
 
         // Check if we can call driver entry function
         let img_guard = img2.lock();
@@ -255,51 +261,50 @@ fn kern_main() -> ! {
     
     // Some tests just to test out process and thread subsystem
     //{
-    //    let spawn_proc = sched::create_process(process_spawn, false).expect("Failed to create second process");
+    //    let spawn_proc = sched::create_process(process_spawn, false, None).expect("Failed to create second process");
     //    info!("Main task waiting for process id 1 to complete");
     //    spawn_proc.wait().expect("Unable to wait on process id 1");
     //    
-    //    let spawn_task = sched::create_thread(task_spawn).unwrap();
+    //    let spawn_task = sched::create_thread(task_spawn, None).unwrap();
 
     //    info!("Main task waiting for task id 1 to complete");
     //    spawn_task.wait().expect("Unable to wait on task id 1");
     //}
 
     //{
-    //    let user_proc0 = sched::create_process(|| -> ! {loop{}}, true)
+    //    let user_proc0 = sched::create_process(|| -> ! {loop{}}, true, None)
     //    .expect("Failed to create user process 0");
     //    
-    //    sched::create_thread(watchdog).unwrap();
+    //    sched::create_thread(watchdog, None).unwrap();
     //}
 
-    //sched::create_thread(thread_creator).expect("Failed to create kernel thread!");
+    //sched::create_thread(thread_creator, None).expect("Failed to create kernel thread!");
 
     //sched::create_thread(|| {
     //    loop {
     //        {
-    //            let mut queue = QUEUE.lock();
+    //            let mut queue = QUEUE.lock().expect("Failed to lock queue from producer!");
     //            queue.add_node([0; 64]).expect("Failed to add node from producer!");
     //            let addr = queue.last().unwrap().as_ptr().addr();
     //            debug!("Added new node at address {:#X}", addr);
     //        }
+    //        QUEUE_READY.notify_one();
     //        sched::delay_ms(1000);
     //    }
-    //}).expect("Failed to create producer thread!");
+    //}, None).expect("Failed to create producer thread!");
 
     //sched::create_process(|| {
     //   loop {
     //        {
-    //            let mut queue = QUEUE.lock();
-    //            let node = queue.first();
-    //            if node.is_some() {
-    //                let node = node.unwrap();
-    //                debug!("[Consumer]: Found node at address: {:#X}", node.as_ptr().addr());
-    //            }
+    //            let queue = QUEUE.lock().expect("Failed to lock queue from consumer!");
+    //            let mut queue = QUEUE_READY.wait_while(queue, |queue| queue.get_nodes() == 0)
+    //            .expect("Failed to wait on producer!");
+    //            let node = queue.first().unwrap();
+    //            debug!("[Consumer]: Found node at address: {:#X}", node.as_ptr().addr());
     //            queue.pop_node();
     //        }
-    //        sched::delay_ms(1000);
     //   } 
-    //}, false).expect("Failed to create consumer process!");
+    //}, false, None).expect("Failed to create consumer process!");
 
 
     loop {
@@ -331,29 +336,50 @@ unsafe extern "C" fn kern_start(boot_info: *const BootInfo) -> ! {
     hal::init();
 }
 
+// What cores do while they have nothing to run, given as idle=halt, idle=poll or idle=mwait[:<cstate>] on the command line
+// Cores halt if it's missing or makes no sense
+fn parse_idle_strategy(cmdline: &str) -> sched::IdleStrategy {
+    let Some(value) = cmdline.split_ascii_whitespace().find_map(|arg| arg.strip_prefix("idle=")) else {
+        return sched::IdleStrategy::Halt;
+    };
+
+    let strategy = match value.split_once(':').unwrap_or((value, "")) {
+        ("halt", "") => Some(sched::IdleStrategy::Halt),
+        ("poll", "") => Some(sched::IdleStrategy::Poll),
+        ("mwait", "") => Some(sched::IdleStrategy::Mwait { cstate: 1 }),
+        ("mwait", cstate) => cstate.parse().ok().map(|cstate| sched::IdleStrategy::Mwait { cstate }),
+        _ => None
+    };
+
+    strategy.unwrap_or_else(|| {
+        info!("Unknown idle strategy {}, using halt instead", value);
+        sched::IdleStrategy::Halt
+    })
+}
+
 static KEYBOARD_EVENT: Once<KSem> = Once::new();
 
 fn key_notifier(_: usize) {
-    let stack_base = hal::get_current_stack_base();
-    info!("Interrupt stack base = {:#X}", stack_base);
-    let avl_memory = mem::get_available_memory();
-    info!("Available memory: {}", avl_memory);
-    let task = sched::get_current_task();
-    if task.is_none() {
-        info!("Called keyboard handler from idle task on core {}", hal::get_core());
-    }
-    else {
-        let task = task.unwrap();
-        let id = task.lock().get_id();
-        let status = task.lock().get_status();
-        info!("Called keyboard handler in task:{} with status: {:?}", id, status);
-    }
-    
-    KEYBOARD_EVENT.get().unwrap().signal();
     clear_keyboard_output_buffer();
 
     // Let the watchdog task know that we're active
     WATCHDOG_MARK.store(true, Ordering::Release);
+
+    // Rest of it can happen outside interrupt context
+    sched::queue_work(&KEY_EVENT_WORK);
+}
+
+// Runs on the worker of the core the event was raised on
+static KEY_EVENT_WORK: sched::Work = sched::Work::new(key_event_work, 0);
+
+fn key_event_work(_: usize) {
+    info!("Handling keyboard event raised on core {}", hal::get_core());
+    let avl_memory = mem::get_available_memory();
+    info!("Available memory: {}", avl_memory);
+    let task = sched::get_current_task().unwrap();
+    info!("Running keyboard work in task:{}", task.lock().get_id());
+
+    KEYBOARD_EVENT.get().unwrap().signal();
 }
 
 static WATCHDOG_MARK: AtomicBool = AtomicBool::new(false);
@@ -9,37 +9,33 @@ use core::mem::{size_of, align_of};
 use core::ptr::copy_nonoverlapping;
 use core::ffi::CStr;
 use common::{PAGE_SIZE, elf::*};
-use common::{ArrayTable, MemoryRegion, ModuleInfo, StrRef};
+use common::{ArrayTable, KERNEL_FILE, MemoryRegion, ModuleInfo, StrRef};
 use kernel_intf::{KError, info};
-use crate::KERNEL_PATH;
 use crate::fs::{FileBuffer, open, resolve_symlink};
 use crate::infra::disable_preloader_phase;
 use crate::loader::module::ModuleDescriptor;
 use crate::mem::{PageDescriptor, PoolAllocatorGlobal, allocate_memory, deallocate_memory};
 use crate::sched::Handle::ImgHandle;
 use crate::sched::add_new_handle;
-use crate::sync::Spinlock;
+use crate::sync::{KMutex, KRwLock, Spinlock};
 use crate::ds::{List, DynList};
 use super::module;
 
-pub static KERNEL_MODULES: Spinlock<DynList<Weak<Spinlock<ModuleDescriptor>, PoolAllocatorGlobal>>> = Spinlock::new(List::new());
+// Images are loaded one at a time. The lock is held across the file I/O of the image and all it's dependencies, hence
+// the sleeping lock. It guards the paths of the images being loaded, so that circular dependencies can be caught
+static LOADS_IN_PROGRESS: KMutex<Vec<String>> = KMutex::new(Vec::new());
+
+// Only written once an image is loaded (Or to clean up), so lookups of loaded images don't wait on a load in progress
+// Lock order => LOADS_IN_PROGRESS -> KERNEL_MODULES
+pub static KERNEL_MODULES: KRwLock<DynList<Weak<Spinlock<ModuleDescriptor>, PoolAllocatorGlobal>>> = KRwLock::new(List::new());
 
 pub type LoadedImage = Arc<Spinlock<ModuleDescriptor>, PoolAllocatorGlobal>;
 
 impl Drop for ModuleDescriptor {
     fn drop(&mut self) {
-        {
-            let mut registry = KERNEL_MODULES.lock();
-            info!("Dropping image {}, with registry_len={}", self.name, registry.get_nodes());
-
-            // Cleanup: Remove all weak refs from the registry
-            while registry.find_and_remove(|entry| {
-                let item = entry.upgrade();
-                item.is_none()
-            }).is_some() {}
-
-            info!("New registry len = {}", registry.get_nodes());
-        }
+        // Registry entry is cleaned up by the next load_image. Images can go away along with a process,
+        // which happens under the scheduler lock where we can't sleep on the registry
+        info!("Dropping image {}", self.name);
 
         deallocate_memory(
             self.info.base as *mut u8, 
@@ -53,7 +49,7 @@ impl Drop for ModuleDescriptor {
 pub fn init() {
     let mut kernel_img = module::ARIS.get().unwrap().lock().clone();
     kernel_img.file_handle = Some(
-        open(KERNEL_PATH).expect("Failed to open kernel image!")
+        open(KERNEL_FILE).expect("Failed to open kernel image!")
     );
 
     let loaded_img = Arc::new_in(
@@ -65,8 +61,9 @@ pub fn init() {
 
     let downgraded_ref = Arc::downgrade(&loaded_img);
 
-    add_new_handle(ImgHandle(loaded_img));
-    KERNEL_MODULES.lock().add_node(downgraded_ref)
+    add_new_handle(ImgHandle(loaded_img))
+    .expect("Failed to add kernel image handle to init process!");
+    KERNEL_MODULES.write().expect("Failed to lock module registry!").add_node(downgraded_ref)
     .expect("Failed to add kernel image module to Loaded images registry!");
 
     disable_preloader_phase();
@@ -74,22 +71,33 @@ pub fn init() {
 
 pub fn load_image(path: &str, is_user: bool) -> Result<LoadedImage, KError> {
     info!("Start load_image for {}", path);
-    let mut in_progress: Vec<String> = Vec::new();
-    let mut registry = KERNEL_MODULES.lock();
-    load_image_inner(path, is_user, &mut in_progress, &mut *registry)
+    if !is_user && let Some(cached) = find_loaded_module(path, &*KERNEL_MODULES.read()?) {
+        info!("Loading image {} from cache", path);
+        return Ok(cached);
+    }
+
+    let mut in_progress = LOADS_IN_PROGRESS.lock()?;
+
+    // Cleanup: Remove the entries of images that were dropped since
+    {
+        let mut registry = KERNEL_MODULES.write()?;
+        while registry.find_and_remove(|entry| entry.strong_count() == 0).is_some() {}
+    }
+
+    load_image_inner(path, is_user, &mut *in_progress)
 }
 
+// Caller holds LOADS_IN_PROGRESS, so nobody else adds images to the registry in the meantime
 fn load_image_inner(
     path: &str, 
     is_user: bool, 
-    in_progress: &mut Vec<String>,
-    registry: &mut DynList<Weak<Spinlock<ModuleDescriptor>, PoolAllocatorGlobal>>
+    in_progress: &mut Vec<String>
 ) -> Result<LoadedImage, KError> {
     if is_user {
         todo!("User-mode image loading not implemented");
     }
 
-    if let Some(cached) = find_loaded_module(path, registry) {
+    if let Some(cached) = find_loaded_module(path, &*KERNEL_MODULES.read()?) {
         info!("Loading image {} from cache", path);
         return Ok(cached);
     }
@@ -102,8 +110,7 @@ fn load_image_inner(
     let result = load_image_uncached(
         path, 
         is_user, 
-        in_progress,
-        registry
+        in_progress
     );
 
     in_progress.pop();
@@ -113,8 +120,7 @@ fn load_image_inner(
 fn load_image_uncached(
     path: &str,
     is_user: bool,
-    in_progress: &mut Vec<String>,
-    registry: &mut DynList<Weak<Spinlock<ModuleDescriptor>, PoolAllocatorGlobal>>
+    in_progress: &mut Vec<String>
 ) -> Result<LoadedImage, KError> {
     info!("Loading image {} from disk", path);
     let file = open(path)?;
@@ -134,7 +140,7 @@ fn load_image_uncached(
     let bytes = buf.as_slice();
 
     let mod_info = build_image_layout(bytes)?;
-    let deps = load_dependencies(&mod_info, is_user, in_progress, registry)?;
+    let deps = load_dependencies(&mod_info, is_user, in_progress)?;
     apply_relocations(&mod_info, &deps)?;
 
     let module_name = configure_module(&mod_info);
@@ -150,7 +156,7 @@ fn load_image_uncached(
     let weak = Arc::downgrade(&arc);
 
     // Add the newly loaded module to the cache
-    registry.add_node(weak)
+    KERNEL_MODULES.write()?.add_node(weak)
     .expect("Failed to add image reference to module registry");
 
     info!("Loaded image '{}' with name={} having module_desc={:?}", path, module_name, arc.lock().info);
@@ -465,8 +471,7 @@ fn build_image_layout(bytes: &[u8]) -> Result<ModuleInfo, KError> {
 fn load_dependencies(
     mod_info: &ModuleInfo,
     is_user: bool,
-    in_progress: &mut Vec<String>,
-    registry: &mut DynList<Weak<Spinlock<ModuleDescriptor>, PoolAllocatorGlobal>>
+    in_progress: &mut Vec<String>
 ) -> Result<Vec<LoadedImage>, KError> {
     let mut deps: Vec<LoadedImage> = Vec::new();
 
@@ -496,8 +501,7 @@ fn load_dependencies(
             let res = load_image_inner(
                 filename.as_str(), 
                 is_user, 
-                in_progress, 
-                registry
+                in_progress
             );
 
             match res {
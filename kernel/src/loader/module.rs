@@ -2,11 +2,11 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use core::alloc::Layout;
 
 use alloc::{collections::BTreeMap, vec::Vec};
-use common::{elf::*, ArrayTable, PAGE_SIZE};
+use common::{elf::*, ArrayTable, KERNEL_FILE, PAGE_SIZE};
 use common::{MemoryRegion, ModuleInfo, FileDescriptor};
 use crate::fs::FileInstance;
 use crate::loader::LoadedImage;
-use crate::{BOOT_INFO, InitFS, KERNEL_PATH, REMAP_LIST, RemapEntry, RemapType::*};
+use crate::{BOOT_INFO, InitFS, REMAP_LIST, RemapEntry, RemapType::*};
 use crate::sync::{Once, Spinlock};
 use kernel_intf::{info, debug};
 use crate::mem::{self, MapFetchType, PageDescriptor};
@@ -279,7 +279,7 @@ pub fn complete_handoff() {
         }
 
         let mut symlinks = BTreeMap::new();
-        symlinks.insert("/sys/libaris.so", KERNEL_PATH);
+        symlinks.insert("/sys/libaris.so", KERNEL_FILE);
 
         InitFS {
             fs: map,
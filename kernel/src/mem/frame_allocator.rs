@@ -14,6 +14,15 @@ const ARCH_PHY_UPPER_LIMIT: u64 = 0xffffffffffffffff;
 #[cfg(target_arch = "x86_64")]
 const ARCH_PHY_LOWER_LIMIT: u64 = 0;
 
+// Extra references to an allocated block, taken by address spaces sharing it copy-on-write
+// A block is only freed once all of them are dropped
+#[derive(Debug)]
+struct SharedBlock {
+    start_phy_address: usize,
+    num_pages: usize,
+    extra_refs: usize
+}
+
 pub struct PhyMemConBlk {
     total_memory: usize,
     avl_memory: usize,
@@ -23,6 +32,7 @@ pub struct PhyMemConBlk {
     lower_limit: u64,
     free_block_list: FixedList<PageDescriptor, {Region0 as usize}>,
     alloc_block_list: FixedList<PageDescriptor, {Region0 as usize}>, 
+    shared_block_list: FixedList<SharedBlock, {Region0 as usize}>
 }
 
 pub static PHY_MEM_CB: Once<Spinlock<PhyMemConBlk>> = Once::new();
@@ -105,6 +115,28 @@ impl PhyMemConBlk {
         Ok(addr)
     }
 
+    // Takes one more reference on an allocated block
+    pub fn share(&mut self, addr: *mut u8, layout: Layout) -> Result<(), KError> {
+        let num_pages = common::ceil_div(layout.size(), PAGE_SIZE);
+        let addr = addr as usize;
+
+        if !self.alloc_block_list.iter().any(|blk| blk.start_phy_address == addr && blk.num_pages == num_pages) {
+            return Err(KError::InvalidArgument);
+        }
+
+        if let Some(blk) = self.shared_block_list.iter_mut().find(|blk| blk.start_phy_address == addr) {
+            blk.extra_refs += 1;
+            return Ok(());
+        }
+
+        self.shared_block_list.add_node(SharedBlock { start_phy_address: addr, num_pages, extra_refs: 1 })
+    }
+
+    pub fn is_shared(&self, addr: *mut u8) -> bool {
+        self.shared_block_list.iter().any(|blk| blk.start_phy_address == addr as usize)
+    }
+
+    // Drops one reference to the block. Memory is only reclaimed once the last reference is gone
     pub fn deallocate(&mut self, addr: *mut u8, layout: Layout) -> Result<(), KError> {
         if layout.align() > PAGE_SIZE {
             return Err(KError::InvalidArgument);
@@ -112,6 +144,22 @@ impl PhyMemConBlk {
 
         let num_pages = common::ceil_div(layout.size(), PAGE_SIZE);
 
+        if let Some(blk) = self.shared_block_list.iter_mut().find(|blk| blk.start_phy_address == addr as usize) {
+            if blk.num_pages != num_pages {
+                return Err(KError::InvalidArgument);
+            }
+
+            blk.extra_refs -= 1;
+            if blk.extra_refs == 0 {
+                let blk = NonNull::from(&*blk);
+                unsafe {
+                    self.shared_block_list.remove_node(blk);
+                }
+            }
+
+            return Ok(());
+        }
+
         // Remove node from alloc_block_list
         let mut alloc_blk = None;
         for blk in self.alloc_block_list.iter() {
@@ -192,7 +240,8 @@ pub fn frame_allocator_init() {
         hard_limit: ARCH_PHY_UPPER_LIMIT,
         lower_limit: ARCH_PHY_LOWER_LIMIT,
         free_block_list: List::new(),
-        alloc_block_list: List::new()
+        alloc_block_list: List::new(),
+        shared_block_list: List::new()
     };
 
     let mem_descriptors  = unsafe {
@@ -279,7 +328,8 @@ pub fn test_init_allocator() {
         hard_limit: ARCH_PHY_UPPER_LIMIT,
         lower_limit: ARCH_PHY_LOWER_LIMIT,
         free_block_list,
-        alloc_block_list: List::new()
+        alloc_block_list: List::new(),
+        shared_block_list: List::new()
     };
 
     PHY_MEM_CB.call_once(|| {
@@ -38,6 +38,8 @@ impl PageDescriptor {
     pub const NO_ALLOC: u8 = 1 << 2;
     pub const MMIO: u8 = 1 << 3;
     pub const WC: u8 = 1 << 4;
+    // Mapped read only. First write gets the address space it's own copy
+    pub const COW: u8 = 1 << 5;
 }
 
 pub fn init() {
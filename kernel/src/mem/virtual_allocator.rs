@@ -570,6 +570,214 @@ impl VirtMemConBlk {
         Ok(new_vcb)
     }
 
+    // Duplicates the user half of the parent address space, which must be the active one
+    // User blocks end up shared copy-on-write between both of them
+    // Returns the new address space along with the physical memory the child now holds a reference to
+    pub fn fork(parent_vcb: VCB, proc_id: usize) -> Result<(VCB, DynList<MemoryRegion>), KError> {
+        info!("Forking user address space for process id {}", proc_id);
+        let child_vcb = Self::clone(get_kernel_addr_space(), proc_id)?;
+
+        // Parent loses write access first, so that both sides start off with the same snapshot
+        let user_blocks = match unsafe { (*parent_vcb.as_ptr()).lock().share_user_blocks() } {
+            Ok(user_blocks) => user_blocks,
+            Err(e) => {
+                unsafe {
+                    Self::destroy_address_space(child_vcb);
+                }
+
+                return Err(e);
+            }
+        };
+
+        let res = Self::map_shared_blocks(child_vcb, &user_blocks).and_then(|_| {
+            let mut shared_memory = List::new();
+            for blk in user_blocks.iter().filter(|blk| blk.is_mapped) {
+                shared_memory.add_node(MemoryRegion { base_address: blk.start_phy_address, size: blk.num_pages * PAGE_SIZE })?;
+            }
+
+            Ok(shared_memory)
+        });
+
+        match res {
+            Ok(shared_memory) => Ok((child_vcb, shared_memory)),
+            Err(e) => {
+                // Child never ran, so the parent gets it's memory back as it was
+                unsafe {
+                    (*parent_vcb.as_ptr()).lock().unshare_user_blocks(&user_blocks);
+                    Self::destroy_address_space(child_vcb);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    // Maps the blocks handed out by share_user_blocks into the child, copy-on-write
+    fn map_shared_blocks(child_vcb: VCB, user_blocks: &FixedList<PageDescriptor, {Region0 as usize}>) -> Result<(), KError> {
+        let page_reserve = Self::get_page_reserve()?;
+        let res = {
+            let mut child = unsafe {
+                (*child_vcb.as_ptr()).lock()
+            };
+
+            user_blocks.iter().try_for_each(|blk| {
+                let size = blk.num_pages * PAGE_SIZE;
+                child.reserve_virtual_space(blk.start_virt_address, Layout::from_size_align(size, PAGE_SIZE).unwrap())?;
+                child.avl_memory -= size;
+
+                if blk.is_mapped {
+                    let flags = blk.flags | PageDescriptor::COW;
+                    child.map_memory(blk.start_phy_address, blk.start_virt_address, size, flags, true)?;
+                    child.page_mapper.map_memory_non_self(
+                        &page_reserve,
+                        blk.start_virt_address,
+                        blk.start_phy_address,
+                        size,
+                        flags
+                    );
+                }
+
+                Ok(())
+            })
+        };
+
+        Self::remove_page_reserve(&page_reserve);
+        res
+    }
+
+    // Write protects all mapped user blocks and takes another reference on their physical memory
+    // Returns the blocks as they were before sharing, which is what unshare_user_blocks needs to roll it back
+    // Must be called on the active address space
+    fn share_user_blocks(&mut self) -> Result<FixedList<PageDescriptor, {Region0 as usize}>, KError> {
+        // Heap allocations are not allowed here, since they could end up locking this address space
+        let mut user_blocks = List::new();
+        let mut res = Ok(());
+
+        for blk in self.alloc_block_list.iter_mut() {
+            if blk.start_virt_address >= KERNEL_HALF_OFFSET {
+                continue;
+            }
+
+            if let Err(e) = user_blocks.add_node((&**blk).clone()) {
+                res = Err(e);
+                break;
+            }
+
+            if blk.is_mapped {
+                let size = blk.num_pages * PAGE_SIZE;
+                if let Err(e) = PHY_MEM_CB.get().unwrap().lock().share(blk.start_phy_address as *mut u8, Layout::from_size_align(size, PAGE_SIZE).unwrap()) {
+                    user_blocks.pop_node();
+                    res = Err(e);
+                    break;
+                }
+
+                if blk.flags & PageDescriptor::COW == 0 {
+                    blk.flags |= PageDescriptor::COW;
+                    self.page_mapper.unmap_memory(blk.start_virt_address, size);
+                    self.page_mapper.map_memory(blk.start_virt_address, blk.start_phy_address, size, blk.flags);
+                    PageMapper::invalidate_other_cores(MemoryRegion { base_address: blk.start_virt_address, size });
+                }
+            }
+        }
+
+        if let Err(e) = res {
+            self.unshare_user_blocks(&user_blocks);
+            return Err(e);
+        }
+
+        Ok(user_blocks)
+    }
+
+    // Drops the references share_user_blocks took and makes the blocks that weren't copy-on-write before writable again
+    // Must be called on the active address space
+    fn unshare_user_blocks(&mut self, user_blocks: &FixedList<PageDescriptor, {Region0 as usize}>) {
+        for shared in user_blocks.iter().filter(|blk| blk.is_mapped) {
+            let size = shared.num_pages * PAGE_SIZE;
+            PHY_MEM_CB.get().unwrap().lock().deallocate(shared.start_phy_address as *mut u8, Layout::from_size_align(size, PAGE_SIZE).unwrap())
+            .expect("Failed to drop reference on shared user block!");
+
+            if shared.flags & PageDescriptor::COW != 0 {
+                continue;
+            }
+
+            // Another thread could have taken a copy of it in the meantime, which is already writable
+            let blk = self.alloc_block_list.iter_mut().find(|blk| {
+                blk.start_virt_address == shared.start_virt_address && blk.start_phy_address == shared.start_phy_address
+                && blk.is_mapped && blk.flags & PageDescriptor::COW != 0
+            });
+
+            if let Some(blk) = blk {
+                blk.flags &= !PageDescriptor::COW;
+                self.page_mapper.unmap_memory(blk.start_virt_address, size);
+                self.page_mapper.map_memory(blk.start_virt_address, blk.start_phy_address, size, blk.flags);
+                PageMapper::invalidate_other_cores(MemoryRegion { base_address: blk.start_virt_address, size });
+            }
+        }
+    }
+
+    // Checks that every page of addr..addr + len is backed by a mapped user block
+    fn is_user_range_mapped(&self, addr: usize, len: usize) -> bool {
+        let end = addr + len;
+        let mut cur = addr;
+
+        while cur < end {
+            let blk = self.alloc_block_list.iter().find(|blk| {
+                blk.start_virt_address <= cur && blk.start_virt_address + blk.num_pages * PAGE_SIZE > cur
+                && blk.is_mapped && blk.flags & PageDescriptor::USER != 0
+            });
+
+            match blk {
+                Some(blk) => cur = blk.start_virt_address + blk.num_pages * PAGE_SIZE,
+                None => return false
+            }
+        }
+
+        true
+    }
+
+    // Returns an address in addr..addr + len that is part of a copy-on-write block
+    fn find_cow_address(&self, addr: usize, len: usize) -> Option<usize> {
+        self.alloc_block_list.iter().find(|blk| {
+            blk.start_virt_address < addr + len && blk.start_virt_address + blk.num_pages * PAGE_SIZE > addr
+            && blk.is_mapped && blk.flags & PageDescriptor::COW != 0
+        }).map(|blk| blk.start_virt_address.max(addr))
+    }
+
+    // Returns (virtual address, physical address, size) of the copy-on-write block that contains addr
+    fn find_cow_block(&self, addr: usize) -> Option<(usize, usize, usize)> {
+        self.alloc_block_list.iter().find(|blk| {
+            blk.start_virt_address <= addr && blk.start_virt_address + blk.num_pages * PAGE_SIZE > addr
+            && blk.is_mapped && blk.flags & PageDescriptor::COW != 0
+        }).map(|blk| (blk.start_virt_address, blk.start_phy_address, blk.num_pages * PAGE_SIZE))
+    }
+
+    // Makes the block writable again, now backed by new_phys (Which is the same as old_phys if it wasn't copied)
+    // Returns false if the block changed in the meantime and the fault needs to be looked at again
+    fn resolve_cow_block(&mut self, virt_addr: usize, old_phys: usize, new_phys: usize) -> bool {
+        // Block might have been shared again (fork) since we decided it's safe to write to it in place
+        if old_phys == new_phys && PHY_MEM_CB.get().unwrap().lock().is_shared(old_phys as *mut u8) {
+            return false;
+        }
+
+        let blk = self.alloc_block_list.iter_mut().find(|blk| {
+            blk.start_virt_address == virt_addr && blk.start_phy_address == old_phys
+            && blk.is_mapped && blk.flags & PageDescriptor::COW != 0
+        });
+
+        if let Some(blk) = blk {
+            let size = blk.num_pages * PAGE_SIZE;
+            blk.flags &= !PageDescriptor::COW;
+            blk.start_phy_address = new_phys;
+
+            self.page_mapper.unmap_memory(virt_addr, size);
+            self.page_mapper.map_memory(virt_addr, new_phys, size, blk.flags);
+            true
+        }
+        else {
+            false
+        }
+    }
+
     // This address space must not be a part of any core
     pub unsafe fn destroy_address_space(vcb: VCB) {
         #[cfg(debug_assertions)]
@@ -707,6 +915,9 @@ pub fn allocate_memory(layout: Layout, flags: u8) -> Result<*mut u8, KError> {
             // Hence just allocate it in the requested address space
             assert!(!(flags & PageDescriptor::USER != 0 && flags & PageDescriptor::NO_ALLOC != 0), "USER and NO_ALLOC flag combination not supported right now");
 
+            // Process limits apply before anything is taken
+            crate::sched::check_memory_limit(layout.size())?;
+
             let active_addr_space = get_active_vcb();
             let virt_addr = unsafe {
                 (*active_addr_space.as_ptr()).lock().allocate(layout, true)?
@@ -1162,6 +1373,163 @@ pub fn virtual_allocator_test() {
     });
 }
 
-pub fn on_page_fault(fault_address: usize) {
-    panic!("Page fault exception!\nFault address:{:#X}", fault_address);
+// Copies a user block of the active address space into newly allocated physical memory
+fn copy_user_block(virt_addr: usize, layout: Layout) -> Result<usize, KError> {
+    let new_phys = allocate_memory(layout, 0)?;
+
+    // Kernel needs it's own view of the new memory to copy into
+    let kernel_view = allocate_memory(layout, PageDescriptor::VIRTUAL | PageDescriptor::NO_ALLOC)?;
+    map_memory(new_phys.addr(), kernel_view.addr(), layout.size(), 0)?;
+
+    unsafe {
+        hal::copy_user_memory(kernel_view, virt_addr as *const u8, layout.size());
+    }
+
+    unmap_memory(kernel_view.addr(), layout.size(), 0)?;
+    deallocate_memory(kernel_view, layout, PageDescriptor::VIRTUAL | PageDescriptor::NO_ALLOC)?;
+
+    Ok(new_phys.addr())
+}
+
+// Gives the active address space it's own copy of the copy-on-write block containing fault_address
+// Returns false if there is no such block
+fn handle_cow_fault(fault_address: usize) -> Result<bool, KError> {
+    let active_vcb = get_active_vcb();
+    let mut is_retry = false;
+
+    loop {
+        let block = unsafe {
+            (*active_vcb.as_ptr()).lock().find_cow_block(fault_address)
+        };
+
+        // Another thread of this process could have resolved it already
+        let (virt_addr, old_phys, size) = match block {
+            Some(block) => block,
+            None => return Ok(is_retry)
+        };
+
+        let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+
+        // If nobody else holds the block anymore, it can just be made writable again
+        let is_shared = PHY_MEM_CB.get().unwrap().lock().is_shared(old_phys as *mut u8);
+        let new_phys = if is_shared {
+            copy_user_block(virt_addr, layout)?
+        }
+        else {
+            old_phys
+        };
+
+        let resolved = unsafe {
+            (*active_vcb.as_ptr()).lock().resolve_cow_block(virt_addr, old_phys, new_phys)
+        };
+
+        if new_phys != old_phys {
+            // Drop whichever copy lost
+            let unused_phys = if resolved {old_phys} else {new_phys};
+            PHY_MEM_CB.get().unwrap().lock().deallocate(unused_phys as *mut u8, layout)?;
+
+            if resolved {
+                crate::sched::replace_memory_range_in_cur_process(old_phys, new_phys);
+            }
+        }
+
+        if resolved {
+            PageMapper::invalidate_other_cores(MemoryRegion { base_address: virt_addr, size });
+            return Ok(true);
+        }
+
+        is_retry = true;
+    }
+}
+
+// Kernel writes to user memory go through here. With CR0.WP set, a kernel write to a copy-on-write page faults just like
+// a user one does, and resolving that from the fault handler means allocating inside an exception. So the copy-on-write
+// blocks in the way are resolved up front, while we're still in the context of the thread
+// The copy itself is done with the address space held, so that a fork can't make the range copy-on-write again under us
+// Range has to be mapped user memory, anything else fails with InvalidArgument instead of faulting
+// Do not call this function from interrupt context
+pub unsafe fn copy_to_user(to: *mut u8, from: *const u8, len: usize) -> Result<(), KError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    if !hal::is_user_range(to.addr(), len) {
+        return Err(KError::InvalidArgument);
+    }
+
+    let active_vcb = get_active_vcb();
+    loop {
+        let cow_address = {
+            let addr_space = unsafe {
+                (*active_vcb.as_ptr()).lock()
+            };
+
+            if !addr_space.is_user_range_mapped(to.addr(), len) {
+                return Err(KError::InvalidArgument);
+            }
+
+            match addr_space.find_cow_address(to.addr(), len) {
+                Some(cow_address) => cow_address,
+                None => {
+                    unsafe {
+                        hal::copy_user_memory(to, from, len);
+                    }
+
+                    return Ok(());
+                }
+            }
+        };
+
+        // Copying the block allocates, so it's done with the address space let go
+        handle_cow_fault(cow_address)?;
+    }
+}
+
+// Kernel reads of user memory that user code pointed us at go through here
+// Range has to be mapped user memory, or we'd take a page fault that can't be recovered from
+// Address space stays locked during the copy, so that another thread can't unmap the range under us
+pub unsafe fn copy_from_user(to: *mut u8, from: *const u8, len: usize) -> Result<(), KError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    if !hal::is_user_range(from.addr(), len) {
+        return Err(KError::InvalidArgument);
+    }
+
+    let addr_space = unsafe {
+        (*get_active_vcb().as_ptr()).lock()
+    };
+
+    if !addr_space.is_user_range_mapped(from.addr(), len) {
+        return Err(KError::InvalidArgument);
+    }
+
+    unsafe {
+        hal::copy_user_memory(to, from, len);
+    }
+
+    Ok(())
+}
+
+pub fn on_page_fault(fault_address: usize, is_user: bool) {
+    // Kernel never faults on user memory, since copy_to_user keeps copy-on-write blocks out of the way
+    // So a kernel fault is a kernel bug
+    if !is_user {
+        panic!("Page fault exception!\nFault address:{:#X}", fault_address);
+    }
+
+    // Copy-on-write faults are the only ones we can recover from for now. Anything else takes the process down, not the kernel
+    // Signal is taken on the way back to user mode, before the faulting instruction is retried
+    match handle_cow_fault(fault_address) {
+        Ok(true) => {},
+        Ok(false) => {
+            debug!("Unresolved user page fault at {:#X}", fault_address);
+            crate::sched::force_signal(crate::sched::SIGSEGV);
+        },
+        Err(e) => {
+            debug!("Failed to resolve copy-on-write fault at {:#X}: {:?}", fault_address, e);
+            crate::sched::force_signal(crate::sched::SIGKILL);
+        }
+    }
 }
\ No newline at end of file
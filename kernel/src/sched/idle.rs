@@ -0,0 +1,63 @@
+use crate::cpu::{MAX_CPUS, PerCpu};
+use crate::hal;
+use crate::sync::Spinlock;
+use core::sync::atomic::AtomicUsize;
+use kernel_intf::{KError, info};
+
+// What a core does while it has nothing to run. Idle time is accounted the same way for all of them
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum IdleStrategy {
+    // Halt till the next interrupt
+    #[default]
+    Halt,
+    // Like Halt, but lets the core drop into a deeper C-state (1 => C1 and so on). Needs MONITOR/MWAIT
+    Mwait { cstate: u8 },
+    // Spin with interrupts enabled. Quickest to wake up, but burns the core. Meant for latency benchmarking
+    Poll
+}
+
+static IDLE_STRATEGY: Spinlock<IdleStrategy> = Spinlock::new(IdleStrategy::Halt);
+
+// Cache line each core monitors while in MWAIT. Cores are still woken up through interrupts, nobody writes here
+static IDLE_MONITOR: PerCpu<AtomicUsize> = PerCpu::new_with([const {AtomicUsize::new(0)}; MAX_CPUS]);
+
+// Meant to be called at boot, but can be changed anytime. Cores pick it up the next time they go idle
+// Fails with KError::InvalidArgument if the cpu can't do it
+pub fn set_idle_strategy(strategy: IdleStrategy) -> Result<(), KError> {
+    if let IdleStrategy::Mwait { cstate } = strategy && hal::get_mwait_hint(cstate).is_none() {
+        return Err(KError::InvalidArgument);
+    }
+
+    info!("Using idle strategy {:?}", strategy);
+    *IDLE_STRATEGY.lock() = strategy;
+
+    Ok(())
+}
+
+pub fn get_idle_strategy() -> IdleStrategy {
+    *IDLE_STRATEGY.lock()
+}
+
+// Body of the idle task. We only leave it when the scheduler switches to a task from an interrupt
+pub(super) fn idle_loop() -> ! {
+    match get_idle_strategy() {
+        IdleStrategy::Halt => loop {
+            hal::wait_for_interrupt();
+        },
+        IdleStrategy::Mwait { cstate } => {
+            let hint = hal::get_mwait_hint(cstate).expect("MWAIT idle strategy set without cpu support!");
+            let monitor_addr = IDLE_MONITOR.local() as *const AtomicUsize as usize;
+
+            loop {
+                hal::wait_for_interrupt_with_hint(monitor_addr, hint);
+            }
+        },
+        IdleStrategy::Poll => {
+            hal::enable_interrupts(true);
+
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
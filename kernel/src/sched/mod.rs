@@ -2,13 +2,20 @@ mod scheduler;
 mod proc;
 mod user;
 mod timer;
+mod work;
+mod signal;
+mod idle;
 
 pub use proc::*;
 pub use scheduler::*;
 pub use user::*;
 pub use timer::*;
+pub use work::*;
+pub use signal::*;
+pub use idle::*;
 
 pub fn init() {
     proc::init();
     scheduler::init();
+    work::init();
 }
\ No newline at end of file
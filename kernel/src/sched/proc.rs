@@ -1,30 +1,85 @@
 use alloc::sync::Arc;
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use alloc::string::String;
 use common::{MemoryRegion, PAGE_SIZE};
 use kernel_intf::{KError, info, debug};
 use crate::fs::FileInstance;
 use crate::loader::LoadedImage;
 use crate::{ds::*, sched};
-use crate::hal;
+use crate::hal::{self, UserContext};
 use crate::mem::{self, PageDescriptor, PoolAllocatorGlobal, VCB, VirtMemConBlk, deallocate_memory, get_physical_address};
 use crate::sched::*;
-use crate::sync::{KSem, Spinlock};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::{KSem, RwSpinlock, Spinlock};
 use core::ptr::NonNull;
+use core::mem::take;
 use core::alloc::Layout;
 
-static PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
-static PROCESSES: Spinlock<BTreeMap<usize, KProcess>> = Spinlock::new(BTreeMap::new());
+// Default maximum number of processes alive at any time. Can be changed with set_max_processes()
+pub const MAX_PROCESSES: usize = 1 << 12;
+
+// Exit statuses kept around for a parent that doesn't get to waiting on them. Oldest ones are dropped past that
+const MAX_ZOMBIES: usize = 64;
+
+static PROCESS_IDS: Spinlock<IdAllocator> = Spinlock::new(IdAllocator::new(MAX_PROCESSES, ID_GRACE_PERIOD));
+// Looked up far more often than processes come and go
+static PROCESSES: RwSpinlock<BTreeMap<usize, KProcess>> = RwSpinlock::new(BTreeMap::new());
 
 pub type KProcess = Arc<Spinlock<Process>, PoolAllocatorGlobal>;
 
+#[derive(Clone)]
 pub enum Handle {
     FileHandle(FileInstance),
     ImgHandle(LoadedImage)
 }
 
-#[derive(Clone, Copy, PartialEq)]
+// Exit status of a child that it's parent hasn't collected yet
+#[derive(Clone, Copy, Debug)]
+pub struct ChildExit {
+    pub id: usize,
+    // None if the child was killed
+    pub exit_code: Option<i64>
+}
+
+// Caps on what a process can hold at any time. Going over one fails with KError::LimitExceeded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResourceLimits {
+    pub max_threads: usize,
+    // In bytes. Covers all the memory in the process memory list (user stacks, user allocations)
+    pub max_memory: usize,
+    pub max_handles: usize
+}
+
+impl ResourceLimits {
+    pub const UNLIMITED: Self = Self {
+        max_threads: usize::MAX,
+        max_memory: usize::MAX,
+        max_handles: usize::MAX
+    };
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+// Snapshot of a process, as handed out by for_each_process
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub id: usize,
+    pub name: Option<String>,
+    pub status: ProcessStatus,
+    pub parent_id: usize,
+    pub is_user: bool,
+    pub num_threads: usize,
+    // Summed over all threads that ever ran in the process. Cpu time is stats.runtime
+    pub stats: SchedStats,
+    pub memory_usage: usize,
+    pub num_handles: usize
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ProcessStatus {
     Ready,
     Terminated
@@ -32,15 +87,34 @@ pub enum ProcessStatus {
 
 pub struct Process {
     id: usize,
+    name: Option<String>,
     // In current design, we will have the process struct holding weak pointers to the tasks.
     // Intuitively it should be the other way around, however this way it makes it easier code wise.
     // When tasks are dropped, the process struct will be automatically dropped
     threads: DynList<usize>,
+    // Process that created this one. Orphans are handed over to process 0, which doesn't keep their exit status
+    parent: usize,
+    orphaned: bool,
+    children: DynList<KProcess>,
+    // Children that exited but haven't been waited on
+    zombies: DynList<ChildExit>,
+    child_notify: KSem,
     addr_space: VCB,
     status: ProcessStatus,
     is_user: bool,
+    // Default affinity for threads created within this process
+    affinity: CoreMask,
+    // Stats of the threads that have already exited
+    exited_stats: SchedStats,
     term_notify: KSem,
     init_notify: KSem,
+    // None if the process was killed instead of exiting on it's own
+    exit_code: Option<i64>,
+    signals: SignalState,
+    // Inherited by child processes
+    limits: ResourceLimits,
+    // Total size of the ranges in memory_list
+    memory_usage: usize,
 
     file_table: Vec<Option<Handle>>,
 
@@ -52,29 +126,54 @@ pub struct Process {
 unsafe impl Send for Process {}
 
 impl Process {
-    fn new(clone_addr_space: bool, is_user: bool) -> Result<KProcess, KError> {
-        let id = PROCESS_ID.fetch_add(1, Ordering::Relaxed);  
-        let kernel_addr_space = mem::get_kernel_addr_space();
-        let new_addr_space = if clone_addr_space {
-            VirtMemConBlk::clone(kernel_addr_space, id)?     
-        }
-        else {
-            kernel_addr_space
+    fn new(clone_addr_space: bool, is_user: bool, parent: usize, name: Option<&str>) -> Result<KProcess, KError> {
+        Self::new_with(is_user, parent, name, |id| {
+            let kernel_addr_space = mem::get_kernel_addr_space();
+            if clone_addr_space {
+                VirtMemConBlk::clone(kernel_addr_space, id)
+            }
+            else {
+                Ok(kernel_addr_space)
+            }
+        })
+    }
+
+    // create_addr_space is handed the id of the new process
+    fn new_with(is_user: bool, parent: usize, name: Option<&str>, create_addr_space: impl FnOnce(usize) -> Result<VCB, KError>) -> Result<KProcess, KError> {
+        let id = PROCESS_IDS.lock().alloc()?;
+        let new_addr_space = match create_addr_space(id) {
+            Ok(vcb) => vcb,
+            Err(e) => {
+                assert!(PROCESS_IDS.lock().free(id), "Process id was not allocated!");
+                return Err(e);
+            }
         };
 
         let proc = Arc::new_in(Spinlock::new(Self {
             id,
+            name: name.map(String::from),
             threads: List::new(),
+            parent,
+            orphaned: false,
+            children: List::new(),
+            zombies: List::new(),
+            child_notify: KSem::new(0, isize::MAX),
             addr_space: new_addr_space,
             status: ProcessStatus::Ready,
             is_user,
+            affinity: CoreMask::all(),
+            exited_stats: SchedStats::default(),
             term_notify: KSem::new(0, 1),
             init_notify: KSem::new(0, 1),
+            exit_code: None,
+            signals: SignalState::new(),
+            limits: ResourceLimits::UNLIMITED,
+            memory_usage: 0,
             memory_list: List::new(),
             file_table: Vec::new()
         }), PoolAllocatorGlobal);
         
-        info!("Creating new process with id {}", id);
+        info!("Creating new process {} with id {}", name.unwrap_or(UNNAMED), id);
 
         Ok(proc)
     }
@@ -95,10 +194,49 @@ impl Process {
         self.id
     }
 
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn get_parent_id(&self) -> usize {
+        self.parent
+    }
+
+    pub fn get_affinity(&self) -> CoreMask {
+        self.affinity
+    }
+
+    pub fn set_exit_code(&mut self, exit_code: Option<i64>) {
+        self.exit_code = exit_code;
+    }
+
+    pub fn get_signal_state(&mut self) -> &mut SignalState {
+        &mut self.signals
+    }
+
+    pub fn add_exited_thread_stats(&mut self, stats: &SchedStats) {
+        self.exited_stats.accumulate(stats);
+    }
+
+    pub fn get_limits(&self) -> ResourceLimits {
+        self.limits
+    }
+
     pub fn attach_thread_to_current_process(&mut self, thread_id: usize) -> Result<(), KError> {
+        if self.threads.get_nodes() >= self.limits.max_threads {
+            return Err(KError::LimitExceeded);
+        }
+
         self.threads.add_node(thread_id)
     }
 
+    fn check_memory_limit(&self, size: usize) -> Result<(), KError> {
+        match self.memory_usage.checked_add(size) {
+            Some(usage) if usage <= self.limits.max_memory => Ok(()),
+            _ => Err(KError::LimitExceeded)
+        }
+    }
+
     pub fn remove_thread(&mut self, thread_id: usize) -> bool {
         let mut killed_thread = None;
         for node in self.threads.iter() {
@@ -145,7 +283,7 @@ impl Process {
 
     fn destroy_process(&mut self) {
         self.status = ProcessStatus::Terminated;
-        PROCESSES.lock().remove(&self.id);
+        PROCESSES.write().remove(&self.id);
         kernel_intf::debug!("Called destroy process {}", self.id);
     }
 
@@ -179,7 +317,7 @@ impl Process {
 
 impl Drop for Process {
     fn drop(&mut self) {
-        info!("Dropping process {}", self.id);
+        info!("Dropping process {} ({})", self.id, self.get_name().unwrap_or(UNNAMED));
         unsafe {
             VirtMemConBlk::destroy_address_space(self.addr_space);
         }
@@ -190,15 +328,17 @@ impl Drop for Process {
             deallocate_memory(range.base_address as *mut u8, Layout::from_size_align(range.size, PAGE_SIZE).unwrap(), 0)
             .expect("Failed to deallocate physical memory from process");
         }
+
+        assert!(PROCESS_IDS.lock().free(self.id), "Process id was not allocated!");
     }
 }
 
 pub fn init() {
     // Create init process and attach init task (task id = 0) to it
-    let init_proc = Process::new(false, false)
+    let init_proc = Process::new(false, false, 0, Some("kernel"))
     .expect("Failed to create init process");
 
-    PROCESSES.lock().insert(0, Arc::clone(&init_proc));
+    PROCESSES.write().insert(0, Arc::clone(&init_proc));
 
     let mut proc = init_proc.lock();
     proc.status = ProcessStatus::Ready;
@@ -207,6 +347,39 @@ pub fn init() {
     info!("Created init process 0");
 }
 
+// Calls f with a snapshot of every process in the system, in order of id
+// Same deal as for_each_task. No lock is held while f runs, so it's free to block, create processes
+// or go back to PROCESSES / TASKS. It just won't see processes created after the snapshot
+pub fn for_each_process<F: FnMut(&ProcessInfo)>(mut f: F) {
+    // Guard is dropped at the end of this statement
+    let processes: Vec<KProcess> = PROCESSES.read().values().cloned().collect();
+
+    for process in processes.iter() {
+        let mut info = {
+            let guard = process.lock();
+            ProcessInfo {
+                id: guard.id,
+                name: guard.name.clone(),
+                status: guard.status,
+                parent_id: guard.parent,
+                is_user: guard.is_user,
+                num_threads: guard.threads.get_nodes(),
+                stats: SchedStats::default(),
+                memory_usage: guard.memory_usage,
+                num_handles: guard.file_table.iter().filter(|handle| handle.is_some()).count()
+            }
+        };
+
+        info.stats = process.get_stats();
+        f(&info);
+    }
+}
+
+// Only limits process creation from here on
+pub fn set_max_processes(max_processes: usize) {
+    PROCESS_IDS.lock().set_max_ids(max_processes);
+}
+
 pub fn get_current_process() -> Option<KProcess> {
     let task = get_current_task()?;
     let guard = task.lock();
@@ -218,17 +391,30 @@ pub fn get_current_process_id() -> Option<usize> {
 }
 
 pub fn get_process_info(proc_id: usize) -> Option<KProcess> {
-    let proc_map = PROCESSES.lock();
+    let proc_map = PROCESSES.read();
 
     proc_map.get(&proc_id).map(|item| {
         Arc::clone(item)
     })
 }
 
-pub fn create_process(start_function: fn() -> !, is_user: bool) -> Result<KProcess, KError> {
+// Init thread of the process is created in the Normal class if no priority is given
+pub fn create_process(start_function: fn() -> !, is_user: bool, priority: Option<TaskPriority>) -> Result<KProcess, KError> {
+    create_named_process(start_function, is_user, priority, None)
+}
+
+// Init thread of the process takes the same name
+// Whoever creates a process out of an image passes the path it was loaded from as the name
+pub fn create_named_process(start_function: fn() -> !, is_user: bool, priority: Option<TaskPriority>, name: Option<&str>) -> Result<KProcess, KError> {
     disable_preemption();
 
-    let process = match Process::new(true, is_user) {
+    let parent = get_current_process().unwrap_or_else(|| get_process_info(0).unwrap());
+    let (parent_id, limits) = {
+        let guard = parent.lock();
+        (guard.get_id(), guard.limits)
+    };
+
+    let process = match Process::new(true, is_user, parent_id, name) {
         Ok(p) => p,
         Err(e) => {
             enable_preemption();
@@ -236,11 +422,24 @@ pub fn create_process(start_function: fn() -> !, is_user: bool) -> Result<KProce
         }
     };
 
+    process.lock().limits = limits;
+
+    // Child is registered before it can run, so that it can't exit behind the parent's back
+    if let Err(e) = parent.lock().children.add_node(Arc::clone(&process)) {
+        enable_preemption();
+        return Err(e);
+    }
+
+    let remove_child = || {
+        parent.lock().children.find_and_remove(|child| Arc::ptr_eq(child, &process));
+    };
+
     let init_notify_sem = process.lock().init_notify.clone();
 
-    let thread = match sched::create_init_thread(start_function, Arc::clone(&process)) {
+    let thread = match sched::create_init_thread(start_function, Arc::clone(&process), priority.unwrap_or_default()) {
         Ok(t) => t,
         Err(e) => {
+            remove_child();
             enable_preemption();
             return Err(e);
         }
@@ -249,6 +448,7 @@ pub fn create_process(start_function: fn() -> !, is_user: bool) -> Result<KProce
     let core = thread.lock().get_core();
 
     if let Err(e) = start_task(&thread, core, &process, &PROCESSES) {
+        remove_child();
         enable_preemption();
         return Err(e);
     }
@@ -262,7 +462,93 @@ pub fn create_process(start_function: fn() -> !, is_user: bool) -> Result<KProce
     Ok(process)
 }
 
+// Duplicates the current (user) process. Child gets a copy-on-write view of the parent's memory,
+// the parent's handles and signal dispositions, and a single thread that resumes with context
+// Returns the child id. The child sees 0 as the return value, which is up to the caller to set in context
+pub fn fork(context: &UserContext) -> Result<usize, KError> {
+    let parent = get_current_process().ok_or(KError::InvalidArgument)?;
+    // Deadline reservations and priorities inherited through a KPiMutex aren't passed on
+    let priority = match get_current_task().ok_or(KError::InvalidArgument)?.get_base_priority() {
+        TaskPriority::Deadline => TaskPriority::Normal,
+        priority => priority
+    };
+
+    let (parent_id, parent_vcb, is_user, name) = {
+        let guard = parent.lock();
+        (guard.get_id(), guard.get_vcb(), guard.get_user_flag(), guard.name.clone())
+    };
+
+    if !is_user {
+        return Err(KError::InvalidArgument);
+    }
+
+    disable_preemption();
+
+    let mut shared_memory = None;
+    let process = match Process::new_with(true, parent_id, name.as_deref(), |id| {
+        let (vcb, memory) = VirtMemConBlk::fork(parent_vcb, id)?;
+        shared_memory = Some(memory);
+        Ok(vcb)
+    }) {
+        Ok(p) => p,
+        Err(e) => {
+            enable_preemption();
+            return Err(e);
+        }
+    };
+
+    let inherited = {
+        let guard = parent.lock();
+        (guard.file_table.clone(), guard.affinity, guard.signals.inherit(), guard.limits)
+    };
+
+    // Child holds it's own reference to all the shared memory, which is dropped along with it
+    let child_id = {
+        let mut child = process.lock();
+        (child.file_table, child.affinity, child.signals, child.limits) = inherited;
+        child.memory_list = shared_memory.unwrap();
+        child.memory_usage = child.memory_list.iter().map(|range| range.size).sum();
+        child.id
+    };
+
+    if let Err(e) = start_forked_process(&parent, &process, priority, context) {
+        // Child never ran, so dropping the last reference to it tears it down (Process::drop). That destroys it's
+        // copy-on-write address space, drops it's references on the shared memory, closes it's handles and frees it's id
+        parent.lock().children.find_and_remove(|child| Arc::ptr_eq(child, &process));
+        drop(process);
+
+        enable_preemption();
+        return Err(e);
+    }
+
+    enable_preemption();
+
+    info!("Forked process {} from process {}", child_id, parent_id);
+    Ok(child_id)
+}
+
+// Registers a forked child with it's parent and starts it's only thread. Nothing is left running if this fails
+fn start_forked_process(parent: &KProcess, process: &KProcess, priority: TaskPriority, context: &UserContext) -> Result<(), KError> {
+    // Limits could have been lowered since the parent allocated it's memory, so the duplicate is checked as a whole
+    process.lock().check_memory_limit(0)?;
+
+    parent.lock().children.add_node(Arc::clone(process))?;
+
+    let mut child_context = *context;
+    child_context.set_return_value(0);
+
+    let thread = sched::create_forked_thread(Arc::clone(process), priority, child_context)?;
+    let core = thread.lock().get_core();
+
+    start_task(&thread, core, process, &PROCESSES)
+}
+
 pub fn kill_process(proc_id: usize) {
+    kill_process_do_work(proc_id, None);
+}
+
+// Exit code is only recorded by whoever moves the process into the terminated state
+fn kill_process_do_work(proc_id: usize, exit_code: Option<i64>) {
     let proc = get_process_info(proc_id);
     if proc.is_none() {
         return;
@@ -285,10 +571,12 @@ pub fn kill_process(proc_id: usize) {
         }
 
         guard.status = ProcessStatus::Terminated;
+        guard.exit_code = exit_code;
         guard.threads.clone()
     };
 
-    kernel_intf::debug!("Killing process {}", proc_id);
+    let name = proc.get_name();
+    kernel_intf::debug!("Killing process {} ({})", proc_id, name.as_deref().unwrap_or(UNNAMED));
 
     let is_idle_task = cur_task_id.is_none();
     let cur_task_id = if cur_task_id.is_some() {cur_task_id.unwrap()} else {0};
@@ -323,10 +611,15 @@ pub fn kill_process(proc_id: usize) {
 
 /* Important to ensure that no locks are held or that preemption is not disabled during this call */
 pub fn exit_process() -> ! {
+    exit_process_with(0);
+}
+
+// Exit code is handed to whoever joins this process
+pub fn exit_process_with(code: i64) -> ! {
     assert!(super::is_preemption_enabled());
     let proc_id = get_current_process_id().expect("Attempted to kill idle process!!");
 
-    kill_process(proc_id);
+    kill_process_do_work(proc_id, Some(code));
 
     // We could land here. Suppose two thread of a process call exit_process.
     // Only one of them succeeds in acquiring lock and setting status to terminate.
@@ -336,7 +629,147 @@ pub fn exit_process() -> ! {
     hal::sleep();
 }
 
-pub fn add_memory_range_to_cur_process(virtual_base: usize, size: usize, is_user: bool) {
+// Suspends all threads of the process. If the caller is part of the process, it's suspended last
+// Threads created after this call are not affected
+pub fn suspend_process(proc_id: usize) -> Result<(), KError> {
+    let threads = {
+        let proc = get_process_info(proc_id).ok_or(KError::InvalidArgument)?;
+        let guard = proc.lock();
+        if guard.status != ProcessStatus::Ready {
+            return Err(KError::ProcessTerminated);
+        }
+
+        guard.threads.clone()
+    };
+
+    let cur_task_id = get_current_task_id();
+    let mut suspend_self = false;
+    let mut res = Ok(());
+
+    for thread_id in threads.iter() {
+        if cur_task_id == Some(**thread_id) {
+            suspend_self = true;
+            continue;
+        }
+
+        // Thread could have exited in the meantime
+        if get_task_info(**thread_id).is_some() && let Err(e) = suspend_thread(**thread_id) {
+            info!("Failed to suspend thread {} of process {} with error {}", **thread_id, proc_id, e);
+            res = res.and(Err(e));
+        }
+    }
+
+    if suspend_self {
+        suspend_thread(cur_task_id.unwrap())?;
+    }
+
+    res
+}
+
+pub fn resume_process(proc_id: usize) -> Result<(), KError> {
+    let threads = {
+        let proc = get_process_info(proc_id).ok_or(KError::InvalidArgument)?;
+        let guard = proc.lock();
+        guard.threads.clone()
+    };
+
+    // Rest of the threads are resumed even if one fails, and the first error is reported
+    let mut res = Ok(());
+    for thread_id in threads.iter() {
+        // Thread could have exited in the meantime
+        if get_task_info(**thread_id).is_some() && let Err(e) = resume_thread(**thread_id) {
+            info!("Failed to resume thread {} of process {} with error {}", **thread_id, proc_id, e);
+            res = res.and(Err(e));
+        }
+    }
+
+    res
+}
+
+// Called by the scheduler (with it's lock held) once the last thread of the process is gone
+// Hands the exit status over to the parent and orphans the children
+// Returns the semaphore that has to be signalled after the scheduler lock is released
+pub fn on_process_exit(process: &KProcess) -> Option<KSem> {
+    let (id, parent_id, orphaned, exit_code, children) = {
+        let mut guard = process.lock();
+        (guard.id, guard.parent, guard.orphaned, guard.exit_code, take(&mut guard.children))
+    };
+
+    for child in children.iter() {
+        let mut child = child.lock();
+
+        // Child that's already going away finds this process terminated and cleans up after itself
+        if child.status == ProcessStatus::Ready {
+            child.parent = 0;
+            child.orphaned = true;
+        }
+    }
+
+    // Process 0 doesn't wait on the orphans it adopts
+    if orphaned {
+        return None;
+    }
+
+    let parent = get_process_info(parent_id)?;
+    let mut parent = parent.lock();
+    parent.children.find_and_remove(|child| Arc::ptr_eq(child, process));
+
+    // Parent is on it's way out as well, so nobody is going to collect this
+    // Process 0 doesn't wait on it's own children either, same as with orphans
+    if parent.status != ProcessStatus::Ready || parent_id == 0 {
+        return None;
+    }
+
+    // There is no wait syscall, so user processes never collect their children either
+    if !parent.is_user {
+        if parent.zombies.get_nodes() == MAX_ZOMBIES {
+            parent.zombies.find_and_remove(|_| true);
+        }
+
+        // We're under the scheduler lock, so the exit status is just lost if there's no memory for it
+        // Waiter finds the child gone either way
+        if parent.zombies.add_node(ChildExit { id, exit_code }).is_err() {
+            debug!("Dropping exit status of process {}", id);
+        }
+    }
+
+    parent.signals.post_if_handled(SIGCHLD);
+    Some(parent.child_notify.clone())
+}
+
+// Waits for a child of the current process to exit (any child if child_id is None) and collects it's exit status
+// Returns KError::InvalidArgument if there is no such child
+pub fn wait_child(child_id: Option<usize>) -> Result<ChildExit, KError> {
+    let proc = get_current_process().ok_or(KError::InvalidArgument)?;
+    let is_match = |id: usize| child_id.is_none_or(|child_id| child_id == id);
+
+    loop {
+        let (child_notify, child) = {
+            let mut guard = proc.lock();
+            if let Some(zombie) = guard.zombies.find_and_remove(|zombie| is_match(zombie.id)) {
+                return Ok(*zombie);
+            }
+
+            let child = guard.children.iter().find(|child| is_match(child.lock().get_id())).map(|child| Arc::clone(child))
+            .ok_or(KError::InvalidArgument)?;
+
+            (guard.child_notify.clone(), child)
+        };
+
+        if child_id.is_some() {
+            // Zombie is in place by the time the child signals it's termination
+            child.wait()?;
+            child.lock().term_notify.signal();
+        }
+        else {
+            // Signalled once for every child that exits
+            child_notify.wait()?;
+        }
+    }
+}
+
+// Fails if the range would take the process over it's memory limit
+pub fn add_memory_range_to_cur_process(virtual_base: usize, size: usize, is_user: bool) -> Result<(), KError> {
     let flags = if is_user {PageDescriptor::USER} else {0};
     let base_address = get_physical_address(virtual_base, flags)
     .expect("Unable to find physical address for given virtual address from add_memory_range_to_cur_process!");
@@ -349,32 +782,71 @@ pub fn add_memory_range_to_cur_process(virtual_base: usize, size: usize, is_user
     debug!("Adding memory range with virtual_base:{:#X}, phy_base:{:#X} and size {}", virtual_base,
     base_address, size);
 
-    process.lock().memory_list.add_node(range)
-    .expect("Failed to add node to process memory list!");
+    let mut guard = process.lock();
+    guard.check_memory_limit(size)?;
+    guard.memory_list.add_node(range)?;
+    guard.memory_usage += size;
+
+    Ok(())
 }
 
-pub fn add_new_handle(handle: Handle) -> usize {
+// Checked before user memory is allocated, so that the allocation fails early
+pub fn check_memory_limit(size: usize) -> Result<(), KError> {
+    match get_current_process() {
+        Some(process) => process.lock().check_memory_limit(size),
+        None => Ok(())
+    }
+}
+
+// Current process got it's own copy of a shared block, so it's now responsible for that one instead
+pub fn replace_memory_range_in_cur_process(old_base: usize, new_base: usize) {
+    let process = get_current_process()
+    .expect("Called replace_memory_range_in_cur_process() from idle task!");
+
+    let mut guard = process.lock();
+    let range = guard.memory_list.iter_mut().find(|range| range.base_address == old_base)
+    .expect("Replaced memory range not found in process memory list!");
+
+    range.base_address = new_base;
+}
+
+pub fn add_new_handle(handle: Handle) -> Result<usize, KError> {
     let proc = get_current_process()
     .expect("add_new_handle() called in idle task!");
 
     let mut guard = proc.lock();
 
+    let open_handles = guard.file_table.iter().filter(|handle| handle.is_some()).count();
+    if open_handles >= guard.limits.max_handles {
+        return Err(KError::LimitExceeded);
+    }
+
     // If we have free entry in table, then use that
     for fd in 0..guard.file_table.len() {
         if guard.file_table[fd].is_none() {
             guard.file_table[fd] = Some(handle);
-            return fd;
+            return Ok(fd);
         }
     }
 
     // Otherwise, allocate new entry
     guard.file_table.push(Some(handle));
 
-    guard.file_table.len() - 1
+    Ok(guard.file_table.len() - 1)
 }
 
 
 impl Spinlock<Process> {
+    pub fn get_name(&self) -> Option<String> {
+        self.lock().name.clone()
+    }
+
+    // Threads that are already running keep their names
+    pub fn set_name(&self, name: &str) {
+        let name = String::from(name);
+        self.lock().name = Some(name);
+    }
+
     pub fn wait(&self) -> Result<(), KError> {
         let sem = {
             let task = self.lock();
@@ -383,4 +855,72 @@ impl Spinlock<Process> {
 
         sem.wait()
     }
+
+    // Waits for the process to go away and returns it's exit code, or KError::Killed if it was killed
+    pub fn join(&self) -> Result<i64, KError> {
+        // Termination signal may already have been taken by a wait() or an earlier joiner, so don't block on it then
+        // Exit code doesn't change anymore once the process is terminated
+        {
+            let proc = self.lock();
+            if proc.status == ProcessStatus::Terminated {
+                return proc.exit_code.ok_or(KError::Killed);
+            }
+        }
+
+        self.wait()?;
+
+        // Pass the signal on, so that other (and later) joiners get through as well
+        self.lock().term_notify.signal();
+        self.lock().exit_code.ok_or(KError::Killed)
+    }
+
+    // Sum over all the threads that ever ran within this process
+    pub fn get_stats(&self) -> SchedStats {
+        // Clone the list so that process lock isn't held while locking the threads
+        let (mut stats, threads) = {
+            let guard = self.lock();
+            (guard.exited_stats, guard.threads.clone())
+        };
+
+        for thread_id in threads.iter() {
+            // Thread could be exiting right now. It's stats are then either already folded into exited_stats or lost for this call
+            if let Some(thread) = get_task_info(**thread_id) {
+                stats.accumulate(&thread.get_stats());
+            }
+        }
+
+        stats
+    }
+
+    // Lowering a limit below the current usage is allowed. It only stops further growth
+    pub fn set_limits(&self, limits: ResourceLimits) {
+        self.lock().limits = limits;
+    }
+
+    // Applies to all current threads of the process as well as the ones created later
+    // Deadline threads are skipped, since they stay on the core their bandwidth was reserved on. Returns how many were
+    pub fn set_affinity(&self, affinity: CoreMask) -> Result<usize, KError> {
+        if !affinity.is_valid() {
+            return Err(KError::InvalidArgument);
+        }
+
+        // Clone the list so that process lock isn't held while changing the threads
+        let threads = {
+            let mut guard = self.lock();
+            guard.affinity = affinity;
+            guard.threads.clone()
+        };
+
+        // Mask was validated above, so a thread can only refuse it by being a deadline thread
+        let mut skipped = 0;
+        for thread_id in threads.iter() {
+            // Thread might have exited in the meantime
+            if let Some(thread) = get_task_info(**thread_id) && thread.set_affinity(affinity).is_err() {
+                info!("Thread {} is a deadline thread, leaving it's affinity as is", **thread_id);
+                skipped += 1;
+            }
+        }
+
+        Ok(skipped)
+    }
 }
\ No newline at end of file
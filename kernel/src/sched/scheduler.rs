@@ -1,12 +1,15 @@
 use alloc::sync::Arc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
 use common::PAGE_SIZE;
 use crate::cpu::{self, MAX_CPUS, PerCpu, Stack, get_panic_base, get_total_cores, get_worker_stack, set_panic_base};
-use crate::hal::{self, IPIRequestType, create_kernel_context, disable_scheduler_timer, enable_scheduler_timer, fetch_context, get_per_cpu_base, get_per_cpu_data, get_per_cpu_kernel_base, set_per_cpu_base, set_per_cpu_data, switch_context};
+use crate::hal::{self, IPIRequestType, UserContext, create_kernel_context, disable_scheduler_timer, enable_scheduler_timer, enable_scheduler_timer_for, fetch_context, get_per_cpu_base, get_per_cpu_data, get_per_cpu_kernel_base, set_per_cpu_base, set_per_cpu_data, switch_context};
 use crate::mem::{PoolAllocatorGlobal, VCB, get_kernel_addr_space, set_address_space};
 use crate::ds::*;
-use crate::sync::{KSem, KSemInnerType, Spinlock};
-use super::{KProcess, ProcessStatus, get_current_process, get_process_info, KTimerInnerType};
-use core::sync::atomic::{AtomicU8,AtomicUsize, Ordering};
+use crate::sync::{KPiMutexInnerType, KSem, KSemInnerType, RwSpinlock, Spinlock, SpinlockGuard};
+use super::{KProcess, ProcessStatus, get_current_process, get_process_info, on_process_exit, KTimerInnerType};
+use core::sync::atomic::{AtomicU8, Ordering};
 use core::ptr::NonNull;
 use core::mem::take;
 use alloc::collections::BTreeMap;
@@ -17,16 +20,35 @@ use crate::hal::{get_per_cpu_kernel_base_for_core, set_tss_stack};
 
 // This is in milliseconds
 pub const QUANTUM: usize = 10;
-const INIT_QUANTA: usize = 10;
+pub const MAX_PRIORITY_CLASSES: usize = 4;
+// Number of scheduler ticks between two load balancing passes on a core
+const BALANCE_INTERVAL: usize = 10;
+// Deadline tasks can reserve at most 90% of a core. The rest is left for everyone else
+const DEADLINE_UTIL_SCALE: usize = 1000;
+const MAX_DEADLINE_UTIL: usize = 900;
+// Core utilization is reported out of this
+pub const UTIL_SCALE: usize = 1000;
+
+// Shown in logs for tasks and processes without a name
+pub const UNNAMED: &str = "<unnamed>";
 
 pub type KThread = Arc<Spinlock<Task>, PoolAllocatorGlobal>;
+pub type ThreadEntry = Box<dyn FnOnce() + Send + 'static, PoolAllocatorGlobal>;
 
-static TASK_ID: AtomicUsize = AtomicUsize::new(0);
+// Default maximum number of tasks alive at any time. Can be changed with set_max_tasks()
+pub const MAX_TASKS: usize = 1 << 16;
+// Number of ids that have to be freed after a task (or process) id before it's handed out again, so that stale ids
+// don't refer to new tasks right away
+pub const ID_GRACE_PERIOD: usize = 1000;
+
+static TASK_IDS: Spinlock<IdAllocator> = Spinlock::new(IdAllocator::new(MAX_TASKS, ID_GRACE_PERIOD));
 static TASK_CPU: AtomicU8 = AtomicU8::new(0);
-static TASKS: Spinlock<BTreeMap<usize, KThread>> = Spinlock::new(BTreeMap::new());
+// Looked up far more often than tasks come and go
+static TASKS: RwSpinlock<BTreeMap<usize, KThread>> = RwSpinlock::new(BTreeMap::new());
 
 const _: () = {
     assert!(u8::MAX as usize + 1 >= MAX_CPUS);
+    assert!(u64::BITS as usize >= MAX_CPUS);
 };
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -34,21 +56,252 @@ pub enum TaskStatus {
     RUNNING,
     ACTIVE,
     WAITING,
+    SUSPENDED,
     TERMINATED
 }
 
+// Scheduling classes, ordered from highest to lowest priority
+// A task is only picked if all the run queues of higher classes are empty
+// Tasks only enter the Deadline class through set_deadline(), since it needs parameters and admission
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TaskPriority {
+    Deadline,
+    RealTime,
+    #[default]
+    Normal,
+    Idle
+}
+
+impl TaskPriority {
+    // Number of scheduler ticks a task of this class can run before it's rotated out
+    const fn get_quanta(&self) -> usize {
+        match self {
+            // Deadline tasks are never rotated out. They leave once they block, run out of budget or an earlier deadline shows up
+            TaskPriority::Deadline => usize::MAX,
+            TaskPriority::RealTime => 5,
+            TaskPriority::Normal => 10,
+            TaskPriority::Idle => 20
+        }
+    }
+
+    pub fn is_higher_than(&self, other: TaskPriority) -> bool {
+        (*self as usize) < (other as usize)
+    }
+}
+
+// Parameters of a Deadline class task, all in ms (Accounting happens at scheduler tick granularity)
+// Task gets runtime ms of cpu time every period, which it has to get within deadline ms of the period start
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DeadlineParams {
+    pub runtime: usize,
+    pub period: usize,
+    pub deadline: usize
+}
+
+impl DeadlineParams {
+    pub fn is_valid(&self) -> bool {
+        self.runtime > 0 && self.runtime <= self.deadline && self.deadline <= self.period
+    }
+
+    // Share of a core the task reserves, out of DEADLINE_UTIL_SCALE
+    fn get_utilization(&self) -> usize {
+        (self.runtime * DEADLINE_UTIL_SCALE).div_ceil(self.period)
+    }
+}
+
+struct DeadlineState {
+    params: DeadlineParams,
+    // Periods follow each other back to back from here, so they don't drift no matter when the task runs
+    period_start: usize,
+    abs_deadline: usize,
+    // Runtime left in the current period
+    budget: usize,
+    // Time up to which the budget has been charged
+    budget_stamp: usize,
+    // Ran out of budget. Task isn't picked again till it's next period
+    throttled: bool,
+    // Affinity to go back to once the task leaves the class
+    saved_affinity: CoreMask
+}
+
+impl DeadlineState {
+    fn new(params: DeadlineParams, now: usize, saved_affinity: CoreMask) -> Self {
+        Self {
+            params,
+            period_start: now,
+            abs_deadline: now + params.deadline,
+            budget: params.runtime,
+            budget_stamp: now,
+            throttled: false,
+            saved_affinity
+        }
+    }
+
+    // Only called while the task is on the cpu
+    fn charge(&mut self, now: usize) {
+        self.budget = self.budget.saturating_sub(now.saturating_sub(self.budget_stamp));
+        self.budget_stamp = now;
+        self.throttled = self.budget == 0;
+    }
+
+    // Moves on to the period containing now. Periods that were missed entirely are skipped
+    fn replenish(&mut self, now: usize) {
+        let elapsed = now.saturating_sub(self.period_start);
+        if elapsed < self.params.period {
+            return;
+        }
+
+        self.period_start += elapsed - elapsed % self.params.period;
+        self.abs_deadline = self.period_start + self.params.deadline;
+        self.budget = self.params.runtime;
+        self.throttled = false;
+    }
+
+    fn get_next_period(&self) -> usize {
+        self.period_start + self.params.period
+    }
+}
+
+// Set of cores a task is allowed to run on. Bit n stands for core n
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CoreMask(u64);
+
+impl CoreMask {
+    pub const fn all() -> Self {
+        CoreMask(u64::MAX)
+    }
+
+    pub const fn empty() -> Self {
+        CoreMask(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        CoreMask(bits)
+    }
+
+    pub fn from_core(core: usize) -> Self {
+        assert!(core < MAX_CPUS);
+        CoreMask(1 << core)
+    }
+
+    pub fn get_bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, core: usize) -> bool {
+        core < MAX_CPUS && self.0 & (1 << core) != 0
+    }
+
+    pub fn add(&mut self, core: usize) {
+        *self = CoreMask(self.0 | CoreMask::from_core(core).0);
+    }
+
+    // A mask is only usable if atleast one of it's cores is present in the system
+    pub fn is_valid(&self) -> bool {
+        (0..get_total_cores()).any(|core| self.contains(core))
+    }
+
+    fn cores(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..get_total_cores()).filter(|core| self.contains(*core))
+    }
+}
+
+// Scheduler statistics of a task (or the sum over all threads of a process)
+// Times are in TSC ticks. Blocking counts as a voluntary switch, everything else (quanta expiry, preemption, yield) as involuntary
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SchedStats {
+    pub runtime: usize,
+    pub wait_time: usize,
+    pub voluntary_switches: usize,
+    pub involuntary_switches: usize
+}
+
+impl SchedStats {
+    pub fn accumulate(&mut self, other: &SchedStats) {
+        self.runtime += other.runtime;
+        self.wait_time += other.wait_time;
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+    }
+}
+
+// Time (in timestamp ticks) a core spent idle, out of the time since the scheduler came up on it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdleStats {
+    pub idle_time: usize,
+    pub uptime: usize
+}
+
+impl IdleStats {
+    // Share of the time the core was busy since the scheduler came up, out of UTIL_SCALE
+    pub fn get_utilization(&self) -> usize {
+        self.get_utilization_since(&IdleStats::default())
+    }
+
+    // Same, but only over the time between an earlier snapshot of the same core and this one
+    pub fn get_utilization_since(&self, earlier: &IdleStats) -> usize {
+        let period = self.uptime.saturating_sub(earlier.uptime);
+        if period == 0 {
+            return 0;
+        }
+
+        let busy = period.saturating_sub(self.idle_time.saturating_sub(earlier.idle_time));
+        busy * UTIL_SCALE / period
+    }
+}
+
+// Snapshot of a task, as handed out by for_each_task
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: usize,
+    pub name: Option<String>,
+    pub status: TaskStatus,
+    pub priority: TaskPriority,
+    pub core: usize,
+    pub process_id: Option<usize>,
+    // Cpu time is stats.runtime
+    pub stats: SchedStats,
+    // None for the init task, which runs on the boot stack
+    pub stack_base: Option<usize>
+}
+
 pub struct Task {
     id: usize,
+    name: Option<String>,
     is_kernel_mode: bool,
     core: usize,
     stack: Option<Stack>,
     status: TaskStatus,
     context: usize,
     quanta: usize,
+    // Class the task is scheduled in. Can be above base_priority while the task holds a KPiMutex someone more important waits on
+    priority: TaskPriority,
+    // Class the task was given through set_priority()/set_deadline()
+    base_priority: TaskPriority,
+    affinity: CoreMask,
+    stats: SchedStats,
+    // Time at which the task started running (when RUNNING) or started waiting (when WAITING)
+    last_timestamp: usize,
     panic_base: usize,
     user_fn: Option<fn() -> !>,
+    // Closure for threads started through closure_trampoline. Taken out once the thread starts running
+    entry: Option<ThreadEntry>,
     wait_semaphores: DynList<KSemInnerType>,
+    // Which of the wait_semaphores ended the last wait. Only set when there was more than one
+    woken_by: Option<KSemInnerType>,
+    // Priority inheritance state. Only touched by sync::KPiMutex
+    pi_blocked_on: Option<KPiMutexInnerType>,
+    pi_mutexes: Vec<KPiMutexInnerType>,
     term_notify: KSem,
+    // None if the task was killed instead of exiting on it's own
+    exit_code: Option<i64>,
+    // Number of outstanding suspend requests. The task doesn't run as long as this is non zero
+    // A running or waiting task only moves to SUSPENDED when it's switched out or woken up respectively
+    suspend_count: usize,
+    // Only present for tasks in the Deadline class
+    deadline: Option<DeadlineState>,
+    // User context to go back to on the next return to user mode, instead of the one the thread left with (sigreturn, fork)
+    resume_context: Option<UserContext>,
     process: Option<KProcess>,
     vcb: Option<VCB>,
 #[cfg(target_arch="x86_64")]
@@ -56,33 +309,47 @@ pub struct Task {
 }
 
 impl Task {
-    fn new(alloc_stack: bool, core: usize, user_fn: Option<fn() -> !>) -> Result<KThread, KError> {
+    fn new(alloc_stack: bool, core: usize, user_fn: Option<fn() -> !>, priority: TaskPriority, affinity: CoreMask, name: Option<&str>) -> Result<KThread, KError> {
         let stack  = if alloc_stack {
             Some(Stack::new()?)
         } else {
             None
         };
-        let id = TASK_ID.fetch_add(1, Ordering::Relaxed);  
+        let id = TASK_IDS.lock().alloc()?;
 
         if alloc_stack {
-            info!("Creating task with ID:{} and stack_addr={:#X} on core {}", id, stack.as_ref().unwrap().get_stack_base(), core);
+            info!("Creating task {} with ID:{} and stack_addr={:#X} on core {}", name.unwrap_or(UNNAMED), id, stack.as_ref().unwrap().get_stack_base(), core);
         } 
         else {
-            info!("Creating task with ID:{}", id);
+            info!("Creating task {} with ID:{}", name.unwrap_or(UNNAMED), id);
         }
 
         let task = Arc::new_in(Spinlock::new(Task {
             id,
+            name: name.map(String::from),
             is_kernel_mode: true,
             core, 
             stack,
             status: TaskStatus::ACTIVE,
             context: 0,
-            quanta: INIT_QUANTA,
+            quanta: priority.get_quanta(),
+            priority,
+            base_priority: priority,
+            affinity,
+            stats: SchedStats::default(),
+            last_timestamp: hal::read_timestamp(),
             panic_base: 0,
             user_fn,
+            entry: None,
             wait_semaphores: List::new(),
+            woken_by: None,
+            pi_blocked_on: None,
+            pi_mutexes: Vec::new(),
             term_notify: KSem::new(0, 1),
+            exit_code: None,
+            suspend_count: 0,
+            deadline: None,
+            resume_context: None,
             process: None,
             vcb: None,
             #[cfg(target_arch = "x86_64")]
@@ -96,10 +363,22 @@ impl Task {
         self.id
     }
 
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn is_user_thread(&self) -> bool {
         self.user_fn.is_some()
     }
 
+    pub fn set_resume_context(&mut self, context: UserContext) {
+        self.resume_context = Some(context);
+    }
+
+    pub fn take_resume_context(&mut self) -> Option<UserContext> {
+        self.resume_context.take()
+    }
+
     pub fn get_status(&self) -> TaskStatus {
         self.status
     }
@@ -112,6 +391,46 @@ impl Task {
         Some(self.stack.as_ref()?.get_stack_base())
     }
 
+    pub fn get_base_priority(&self) -> TaskPriority {
+        self.base_priority
+    }
+
+    pub fn get_pi_blocked_on(&self) -> Option<KPiMutexInnerType> {
+        self.pi_blocked_on.clone()
+    }
+
+    pub fn set_pi_blocked_on(&mut self, mutex: Option<KPiMutexInnerType>) {
+        self.pi_blocked_on = mutex;
+    }
+
+    pub fn get_pi_mutexes(&self) -> Vec<KPiMutexInnerType> {
+        self.pi_mutexes.clone()
+    }
+
+    // Makes sure the next add_pi_mutex() doesn't need to allocate
+    // A waiter calls this before it blocks, since ownership is handed to it by someone else holding a spinlock
+    pub fn reserve_pi_mutex(&mut self) -> Result<(), KError> {
+        self.pi_mutexes.try_reserve(1).map_err(|_| KError::OutOfMemory)
+    }
+
+    // Gives back the room made by reserve_pi_mutex() when the task stops waiting without getting the mutex
+    pub fn release_pi_mutex(&mut self) {
+        self.pi_mutexes.shrink_to(self.pi_mutexes.len());
+    }
+
+    pub fn add_pi_mutex(&mut self, mutex: KPiMutexInnerType) {
+        self.pi_mutexes.push(mutex);
+    }
+
+    pub fn remove_pi_mutex(&mut self, mutex: &KPiMutexInnerType) {
+        self.pi_mutexes.retain(|held| !Arc::ptr_eq(held, mutex));
+    }
+
+    // Which semaphore woke the task up, after a wait on more than one
+    pub fn take_woken_by(&mut self) -> Option<KSemInnerType> {
+        self.woken_by.take()
+    }
+
     pub fn get_process(&self) -> Option<KProcess> {
         if let Some(proc) = &self.process {
             Some(Arc::clone(proc))
@@ -120,28 +439,84 @@ impl Task {
             None
         }
     }
+
+    // Includes the time of the current run if the task is on the cpu right now
+    pub fn get_stats(&self) -> SchedStats {
+        let mut stats = self.stats;
+        if self.status == TaskStatus::RUNNING {
+            stats.runtime += hal::read_timestamp().saturating_sub(self.last_timestamp);
+        }
+
+        stats
+    }
+
+    // Time a deadline task spends off the cpu isn't charged to it's budget
+    fn restart_budget_clock(&mut self) {
+        if let Some(deadline) = self.deadline.as_mut() {
+            deadline.budget_stamp = hal::get_time_ms();
+        }
+    }
+
+    fn get_abs_deadline(&self) -> Option<usize> {
+        self.deadline.as_ref().map(|deadline| deadline.abs_deadline)
+    }
+
+    fn is_throttled(&self) -> bool {
+        self.deadline.as_ref().is_some_and(|deadline| deadline.throttled)
+    }
+
+    // Must be called before the status of a RUNNING task is changed to ACTIVE
+    fn account_switch_out(&mut self, now: usize) {
+        // Timestamps of different cores could be slightly off, so avoid underflow after a migration
+        self.stats.runtime += now.saturating_sub(self.last_timestamp);
+        if self.status == TaskStatus::RUNNING {
+            self.stats.involuntary_switches += 1;
+        }
+        else {
+            self.stats.voluntary_switches += 1;
+        }
+
+        self.last_timestamp = now;
+    }
 }
 
 impl Drop for Task {
     fn drop(&mut self) {
-        info!("Dropping task:{}", self.id);
+        info!("Dropping task:{} ({})", self.id, self.get_name().unwrap_or(UNNAMED));
         assert!(self.wait_semaphores.get_nodes() == 0);
+
+        assert!(TASK_IDS.lock().free(self.id), "Task id was not allocated!");
     }
 }
 
 unsafe impl Send for Task {}
 
 pub struct TaskQueue {
-    active_tasks: DynList<KThread>,
+    // One run queue per scheduling class, indexed by TaskPriority
+    active_tasks: [DynList<KThread>; MAX_PRIORITY_CLASSES],
+    // Active tasks that aren't allowed on this core anymore, waiting to be pushed to one they are allowed on
+    misplaced_tasks: DynList<KThread>,
     waiting_tasks: DynList<KThread>,
+    suspended_tasks: DynList<KThread>,
     terminated_tasks: DynList<KThread>,
     notifier_list: DynList<KSem>,
+    // Sorted by deadline, so that expiry only ever has to look at the head
     timer_list: DynList<KTimerInnerType>,
     running_task: Option<NonNull<ListNode<KThread>>>,
     idle_task_stack: NonNull<u8>,
     leftover_stack: DynList<Stack>,
     flip_flop: bool,
-    preemption_count: usize
+    preemption_count: usize,
+    last_balance_time: usize,
+    // Sum of the utilization of all the deadline tasks on this core, out of DEADLINE_UTIL_SCALE
+    deadline_util: usize,
+    // Idle time accounting. Time spent in the idle task so far, not counting the stretch that started at idle_stamp
+    // (idle_stamp is only meaningful while running_task is None)
+    idle_time: usize,
+    idle_stamp: usize,
+    start_stamp: usize,
+    // Task switched out by the last schedule call. The cpu is still on it's stack till the interrupt returns
+    switched_out: Option<*const Spinlock<Task>>
 }
 
 unsafe impl Send for TaskQueue{}
@@ -149,8 +524,10 @@ unsafe impl Send for TaskQueue{}
 impl TaskQueue {
     const fn new() -> Self {
         TaskQueue {
-            active_tasks: List::new(),
+            active_tasks: [const {List::new()}; MAX_PRIORITY_CLASSES],
+            misplaced_tasks: List::new(),
             waiting_tasks: List::new(),
+            suspended_tasks: List::new(),
             terminated_tasks: List::new(),
             notifier_list: List::new(),
             timer_list: List::new(),
@@ -158,17 +535,160 @@ impl TaskQueue {
             idle_task_stack: NonNull::dangling(),
             leftover_stack: List::new(),
             flip_flop: false,
-            preemption_count: 0
+            preemption_count: 0,
+            last_balance_time: 0,
+            deadline_util: 0,
+            idle_time: 0,
+            idle_stamp: 0,
+            start_stamp: 0,
+            switched_out: None
+        }
+    }
+
+    fn run_queue(&mut self, priority: TaskPriority) -> &mut DynList<KThread> {
+        &mut self.active_tasks[priority as usize]
+    }
+
+    // Every task in the run queues is allowed on this core, so that picking the next one never has to look at affinity
+    // An active task that isn't (It's affinity was changed under it) is parked in misplaced_tasks instead
+    fn queue_active(&mut self, task_node: NonNull<ListNode<KThread>>, task: &Task, at_head: bool) {
+        let queue = if task.affinity.contains(task.core) {
+            &mut self.active_tasks[task.priority as usize]
+        }
+        else {
+            &mut self.misplaced_tasks
+        };
+
+        if at_head {
+            queue.insert_node_at_head(task_node);
+        }
+        else {
+            queue.insert_node_at_tail(task_node);
+        }
+    }
+
+    // Takes an active task off the run queue of it's class, or off misplaced_tasks
+    fn remove_active(&mut self, task: *const Spinlock<Task>, priority: TaskPriority) -> NonNull<ListNode<KThread>> {
+        let queue = if find_task_node(&self.active_tasks[priority as usize], task).is_some() {
+            &mut self.active_tasks[priority as usize]
+        }
+        else {
+            &mut self.misplaced_tasks
+        };
+
+        let task_node = find_task_node(queue, task).expect("Active task not found in it's run queue!");
+        unsafe {
+            ListNode::into_inner(queue.remove_node(task_node))
         }
     }
+
+    // First task of the highest priority class
+    // Deadline class goes by earliest deadline instead of queue order, and skips throttled tasks
+    fn next_active_task(&self) -> Option<NonNull<ListNode<KThread>>> {
+        let deadline_task = self.active_tasks[TaskPriority::Deadline as usize].iter().filter_map(|task| {
+            let guard = task.lock();
+            (!guard.is_throttled()).then(|| (guard.get_abs_deadline(), NonNull::from(task)))
+        })
+        .min_by_key(|(abs_deadline, _)| *abs_deadline)
+        .map(|(_, task)| task);
+
+        deadline_task.or_else(|| self.active_tasks[TaskPriority::Deadline as usize + 1..].iter().find_map(|queue| {
+            queue.first().map(NonNull::from)
+        }))
+    }
+
+    // Number of tasks which want this cpu (queued + currently running)
+    fn get_load(&self) -> usize {
+        let queued: usize = self.active_tasks.iter().map(|queue| queue.get_nodes()).sum();
+        queued + self.running_task.is_some() as usize
+    }
+}
+
+fn find_task_node(list: &DynList<KThread>, task: *const Spinlock<Task>) -> Option<NonNull<ListNode<KThread>>> {
+    // Pointer comparison, so that the task lock isn't required
+    list.iter().find(|node| core::ptr::eq(Arc::as_ptr(&***node), task)).map(NonNull::from)
 }
 
 static SCHEDULER_CON_BLK: PerCpu<Spinlock<TaskQueue>> = PerCpu::new_with(
     [const {Spinlock::new(TaskQueue::new())}; MAX_CPUS]
 );
 
+// Task could get migrated between reading it's core and locking that core's scheduler
+// Since core is only changed with the scheduler lock of that core held, retry until both agree
+fn lock_task_scheduler(task: &Spinlock<Task>) -> (SpinlockGuard<'static, TaskQueue>, usize) {
+    loop {
+        let core = task.lock().core;
+        let sched_cb = unsafe {
+            SCHEDULER_CON_BLK.get(core).lock()
+        };
+
+        if task.lock().core == core {
+            return (sched_cb, core);
+        }
+    }
+}
+
+// Calls f with a snapshot of every task in the system, in order of id
+// TASKS is only read while the task list is copied out, and no lock at all is held while f runs
+// So f is free to block, create tasks or go back to TASKS / PROCESSES (A nested TASKS read could otherwise get
+// stuck behind a waiting writer). It just won't see tasks created after the snapshot
+pub fn for_each_task<F: FnMut(&TaskInfo)>(mut f: F) {
+    // Guard is dropped at the end of this statement
+    let tasks: Vec<KThread> = TASKS.read().values().cloned().collect();
+
+    for task in tasks.iter() {
+        // Lock order => Process -> Task, so the process is only looked at once the task is unlocked
+        let (mut info, process) = {
+            let guard = task.lock();
+            let info = TaskInfo {
+                id: guard.id,
+                name: guard.name.clone(),
+                status: guard.status,
+                priority: guard.priority,
+                core: guard.core,
+                process_id: None,
+                stats: guard.get_stats(),
+                stack_base: guard.get_stack()
+            };
+
+            (info, guard.get_process())
+        };
+
+        info.process_id = process.map(|process| process.lock().get_id());
+        f(&info);
+    }
+}
+
+pub fn get_idle_stats(core: usize) -> Result<IdleStats, KError> {
+    if core >= get_total_cores() {
+        return Err(KError::InvalidArgument);
+    }
+
+    let sched_cb = unsafe {
+        SCHEDULER_CON_BLK.get(core).lock()
+    };
+
+    let now = hal::read_timestamp();
+    let cur_idle = if sched_cb.running_task.is_none() {
+        now.saturating_sub(sched_cb.idle_stamp)
+    }
+    else {
+        0
+    };
+
+    Ok(IdleStats {
+        idle_time: sched_cb.idle_time + cur_idle,
+        uptime: now.saturating_sub(sched_cb.start_stamp)
+    })
+}
+
+// Only limits task creation from here on
+pub fn set_max_tasks(max_tasks: usize) {
+    TASK_IDS.lock().set_max_ids(max_tasks);
+}
+
 pub fn get_task_info(task_id: usize) -> Option<KThread> {
-    let task_map = TASKS.lock();
+    let task_map = TASKS.read();
 
     task_map.get(&task_id).map(|item| {
         Arc::clone(item)
@@ -208,17 +728,40 @@ pub fn yield_cpu() {
     hal::yield_cpu();
 }
 
+// Deadline tasks call this once they're done with the current period. Sleeps till the next one starts
+pub fn wait_next_period() -> Result<(), KError> {
+    let next_period = get_current_task().ok_or(KError::InvalidArgument)?
+    .lock().deadline.as_ref().ok_or(KError::InvalidArgument)?.get_next_period();
+
+    let now = hal::get_time_ms();
+    if next_period > now {
+        super::delay_ms(next_period - now);
+    }
+
+    Ok(())
+}
+
 pub fn is_preemption_enabled() -> bool {
     SCHEDULER_CON_BLK.local().lock().preemption_count == 0
 }
 
 pub fn init() {
-    let init_task = Task::new(false, 0, None)
+    // Have the lock validator hold us to the lock order
+    #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    crate::sync::lockdep::declare_lock_order(&[
+        "PI_LOCK",
+        core::any::type_name::<crate::sync::KPiMutexInner>(),
+        core::any::type_name::<TaskQueue>(),
+        core::any::type_name::<super::Process>(),
+        core::any::type_name::<Task>()
+    ]);
+
+    let init_task = Task::new(false, 0, None, TaskPriority::Normal, CoreMask::all(), Some("init"))
     .expect("Init task creation failed!!");
     
     let init_proc = get_process_info(0).expect("Unable to locate init process!");
     
-    TASKS.lock().insert(0, Arc::clone(&init_task));
+    TASKS.write().insert(0, Arc::clone(&init_task));
 
     init_task.lock().status = TaskStatus::RUNNING;
     init_task.lock().panic_base = get_panic_base();
@@ -227,12 +770,13 @@ pub fn init() {
 
     {
         let mut sched_cb = SCHEDULER_CON_BLK.local().lock();
-        sched_cb.active_tasks.add_node(init_task).expect("Init task creation failed!");
+        let run_queue = sched_cb.run_queue(TaskPriority::Normal);
+        run_queue.add_node(init_task).expect("Init task creation failed!");
 
-        let task = NonNull::from(sched_cb.active_tasks.first().unwrap());
+        let task = NonNull::from(run_queue.first().unwrap());
 
         unsafe {
-            let guard = sched_cb.active_tasks.remove_node(task);
+            let guard = run_queue.remove_node(task);
             sched_cb.running_task = Some(ListNode::into_inner(guard));
         }
     
@@ -246,6 +790,11 @@ pub fn init() {
             SCHEDULER_CON_BLK.get(core).lock()
         };
 
+        // Cores other than 0 sit in the idle task till they get their first task
+        let now = hal::read_timestamp();
+        sched_cb.start_stamp = now;
+        sched_cb.idle_stamp = now;
+
         // We need to create separate stack for idle task on cpu 0, since the current stack is used by init task
         if core == 0 {
             let stack = Stack::into_inner(&mut Stack::new_with(cpu::WORKER_STACK_SIZE, PAGE_SIZE, false).expect("Could not create worker stack for cpu 0"));
@@ -264,46 +813,91 @@ pub fn init() {
 }
 
 // Set task to waiting and add the timer atomically
-pub fn add_cur_task_to_wait_queue_with_timer(wait_semaphore: KSemInnerType, timer: KTimerInnerType) -> bool {
-    let mut sched_cb = SCHEDULER_CON_BLK.local().lock();
-    let cb = sched_cb.running_task;
-    if cb.is_none() {
-        panic!("add_cur_task_to_wait_queue_with_timer() called from idle task!!");
-    }
-    
-    let cur_task = unsafe { &**cb.unwrap().as_ptr() };
-    let mut task = cur_task.lock();
-    
-    // TERMINATED > WAITING, don't do anything
-    if task.status == TaskStatus::TERMINATED {
-        return false;
+// Arms the timer and puts it in it's sorted position in the timer list
+fn insert_timer(sched_cb: &mut TaskQueue, timer: KTimerInnerType) -> Result<(), KError> {
+    let deadline = {
+        let mut timer = timer.lock();
+        timer.arm(hal::get_time_ms(), hal::get_core());
+        timer.get_deadline()
+    };
+
+    // Timers with the same deadline expire in the order they were added
+    let next_timer = sched_cb.timer_list.iter().find(|item| item.lock().get_deadline() > deadline).map(NonNull::from);
+
+    sched_cb.timer_list.add_node(timer)?;
+
+    // add_node puts it at the tail, move it to it's sorted position
+    if let Some(next_timer) = next_timer {
+        let timer_node = NonNull::from(sched_cb.timer_list.last().unwrap());
+        unsafe {
+            let timer_node = ListNode::into_inner(sched_cb.timer_list.remove_node(timer_node));
+            sched_cb.timer_list.insert_node_before(timer_node, next_timer);
+        }
     }
-    
-    let res = sched_cb.timer_list.add_node(timer);
-    if res.is_err() {
-        return false;
+
+    Ok(())
+}
+
+// Starts a timer on the local core without anyone waiting on it
+// Expiry is only reported through the timer's semaphore. Can be called from interrupt context
+pub fn start_timer(timer: KTimerInnerType) -> Result<(), KError> {
+    {
+        let mut sched_cb = SCHEDULER_CON_BLK.local().lock();
+        insert_timer(&mut sched_cb, timer)?;
     }
 
-    assert!(task.wait_semaphores.get_nodes() == 0);
-    let res = task.wait_semaphores.add_node(wait_semaphore);
-    if res.is_err() {
-        sched_cb.timer_list.pop_node();
-        return false;
+    // The tick could be switched off or programmed past this timer's deadline
+    enable_scheduler_timer();
+    Ok(())
+}
+
+// Takes a timer that hasn't expired yet off the timer list of it's core. Returns false if it isn't queued anywhere
+// Can be called from interrupt context
+pub fn cancel_timer(timer: &KTimerInnerType) -> bool {
+    loop {
+        let Some(core) = timer.lock().get_core() else {
+            return false;
+        };
+
+        let mut sched_cb = unsafe {
+            SCHEDULER_CON_BLK.get(core).lock()
+        };
+
+        // It could have expired in the meantime. Core only changes with the scheduler lock of that core held
+        if timer.lock().get_core() != Some(core) {
+            continue;
+        }
+
+        let timer_node = sched_cb.timer_list.iter().find(|item| Arc::ptr_eq(item, timer)).map(NonNull::from)
+        .expect("Armed timer not found in it's timer list!");
+
+        unsafe {
+            sched_cb.timer_list.remove_node(timer_node);
+        }
+
+        timer.lock().disarm();
+        return true;
     }
+}
 
-    task.status = TaskStatus::WAITING;
-    true
+pub fn add_cur_task_to_wait_queue_with_timer(wait_semaphore: KSemInnerType, timer: KTimerInnerType) -> bool {
+    add_cur_task_to_wait_queue_multi(&[wait_semaphore], Some(timer))
 }
 
 pub fn add_cur_task_to_wait_queue(wait_semaphore: KSemInnerType) -> bool {
-    let sched_cb = SCHEDULER_CON_BLK.local().lock();
+    add_cur_task_to_wait_queue_multi(&[wait_semaphore], None)
+}
+
+// Task waits on all of the semaphores at once and is woken up by whichever signals first
+// Caller must be on the blocked list of every one of them
+pub fn add_cur_task_to_wait_queue_multi(wait_semaphores: &[KSemInnerType], timer: Option<KTimerInnerType>) -> bool {
+    let mut sched_cb = SCHEDULER_CON_BLK.local().lock();
     let cb = sched_cb.running_task;
     if cb.is_none() {
         panic!("add_cur_task_to_wait_queue() called from idle task!!");
     }
     
     let cur_task = unsafe { &**cb.unwrap().as_ptr() };
-
     let mut task = cur_task.lock();
     
     // TERMINATED > WAITING, don't do anything
@@ -312,39 +906,27 @@ pub fn add_cur_task_to_wait_queue(wait_semaphore: KSemInnerType) -> bool {
     }
     
     assert!(task.wait_semaphores.get_nodes() == 0);
+    for wait_semaphore in wait_semaphores {
+        if task.wait_semaphores.add_node(Arc::clone(wait_semaphore)).is_err() {
+            task.wait_semaphores = List::new();
+            return false;
+        }
+    }
 
-    let res = task.wait_semaphores.add_node(wait_semaphore);
-    if res.is_err() {
+    if let Some(timer) = timer && insert_timer(&mut sched_cb, timer).is_err() {
+        task.wait_semaphores = List::new();
         return false;
     }
 
+    task.woken_by = None;
     task.status = TaskStatus::WAITING;
     true
 }
 
-fn remove_wait_semaphore(task: &mut Task, wait_semaphore: KSemInnerType) {
-    let mut sem = None;
-    let sem_val = (&*wait_semaphore) as *const _;
-
-    assert!(task.wait_semaphores.get_nodes() == 1);
-
-    for semaphore in task.wait_semaphores.iter() {
-        let val = (&***semaphore) as *const _;
-
-        if val == sem_val {
-            sem = Some(NonNull::from(semaphore));
-            break;
-        } 
-    }
-
-    if sem.is_some() {
-        unsafe {
-            task.wait_semaphores.remove_node(sem.unwrap());
-        }
-    }
+fn is_waiting_on(task: &Task, wait_semaphore: &KSemInnerType) -> bool {
+    task.wait_semaphores.iter().any(|semaphore| Arc::ptr_eq(semaphore, wait_semaphore))
 }
 
-
 pub fn signal_waiting_task(task_id: usize, wait_semaphore: KSemInnerType) {
     let this_task = get_task_info(task_id);
 
@@ -354,16 +936,18 @@ pub fn signal_waiting_task(task_id: usize, wait_semaphore: KSemInnerType) {
     }
 
     let this_task = this_task.unwrap();
-    let core = this_task.lock().core;
     let mut skip_notify = false;
     disable_preemption();
-    {
-        let mut sched_cb = unsafe {
-            SCHEDULER_CON_BLK.get(core).lock() 
-        };
+    let core = {
+        let (mut sched_cb, core) = lock_task_scheduler(&this_task);
 
         let status = this_task.lock().status;
         match status {
+            // Task was waiting on several semaphores and another one already woke it up. It'll cancel this one itself
+            _ if status != TaskStatus::TERMINATED && !is_waiting_on(&*this_task.lock(), &wait_semaphore) => {
+                skip_notify = true;
+            },
+
             TaskStatus::WAITING => {
                 let mut waiting_task = None;
                 for task in sched_cb.waiting_tasks.iter() {
@@ -378,29 +962,47 @@ pub fn signal_waiting_task(task_id: usize, wait_semaphore: KSemInnerType) {
                 if waiting_task.is_none() {
                     // Let task run again with high priority
                     task.status = TaskStatus::RUNNING;
-                    task.quanta = INIT_QUANTA;
+                    task.quanta = task.priority.get_quanta();
                 }
                 else {
                     let signal_task = unsafe {
                         ListNode::into_inner(sched_cb.waiting_tasks.remove_node(waiting_task.unwrap()))
                     };
                     
-                    sched_cb.active_tasks.insert_node_at_head(signal_task);
-                    task.status = TaskStatus::ACTIVE;
+                    // Task got suspended while it was waiting. The wake up is kept, it just won't run till it's resumed
+                    if task.suspend_count > 0 {
+                        sched_cb.suspended_tasks.insert_node_at_tail(signal_task);
+                        task.status = TaskStatus::SUSPENDED;
+                    }
+                    else {
+                        sched_cb.queue_active(signal_task, &task, true);
+                        task.status = TaskStatus::ACTIVE;
+                    }
+                    
+                    let now = hal::read_timestamp();
+                    task.stats.wait_time += now.saturating_sub(task.last_timestamp);
+                    task.last_timestamp = now;
+                }
+
+                // Only a task waiting on more than one semaphore needs to know which one it was
+                if task.wait_semaphores.get_nodes() > 1 {
+                    task.woken_by = Some(wait_semaphore);
                 }
 
-                remove_wait_semaphore(&mut *task, wait_semaphore);
+                task.wait_semaphores = List::new();
             },
 
             TaskStatus::TERMINATED => {
                 skip_notify = true;
             },
 
-            TaskStatus::ACTIVE | TaskStatus::RUNNING => {
-                panic!("Signalled task {} which was in ACTIVE/RUNNING state??", task_id);
+            TaskStatus::ACTIVE | TaskStatus::RUNNING | TaskStatus::SUSPENDED => {
+                panic!("Signalled task {} which was in {:?} state??", task_id, status);
             }
         }
-    }
+
+        core
+    };
     
     if !skip_notify {
         notify_other_cpu(core);
@@ -419,6 +1021,11 @@ pub fn signal_waiting_task(task_id: usize, wait_semaphore: KSemInnerType) {
 // Doing stack unwinding for every process/task destruction is not practical and can cause lot
 // of bookkeeping and performance issues
 pub fn kill_thread(task_id: usize) {
+    kill_thread_do_work(task_id, None);
+}
+
+// Exit code is only recorded if the task wasn't already terminated
+fn kill_thread_do_work(task_id: usize, exit_code: Option<i64>) {
     let mut yield_flag = false;
     let mut drop_task  = false;
     let mut skip_notify  = false;
@@ -430,37 +1037,37 @@ pub fn kill_thread(task_id: usize) {
     }
 
     let this_task  = this_task.unwrap();
-    let core = this_task.lock().core;
     
     disable_preemption();
 
     assert!(task_id != 0, "Attempted to kill init task!!!");
-    {
-        let mut sched_cb = unsafe {
-            SCHEDULER_CON_BLK.get(core).lock()
-        };
+    let core = {
+        let (mut sched_cb, core) = lock_task_scheduler(&this_task);
 
-        let status = {
+        let (status, priority) = {
             let mut task_locked= this_task.lock();
             let status = task_locked.status;
+            if status != TaskStatus::TERMINATED {
+                task_locked.exit_code = exit_code;
+            }
+
             task_locked.status = TaskStatus::TERMINATED;
-            status 
+            (status, task_locked.priority)
         };
 
         // Remove task from active list and add to terminated list
         match status {
             TaskStatus::ACTIVE => {
-                let mut task_l = None;
-                for active_task in sched_cb.active_tasks.iter() {
-                    if active_task.lock().id == task_id {
-                        task_l = Some(NonNull::from(active_task));
-                        break;
-                    }
-                }
+                let task_node = sched_cb.remove_active(&*this_task, priority);
+                sched_cb.terminated_tasks.insert_node_at_tail(task_node);
+            },
+
+            TaskStatus::SUSPENDED => {
+                let task_node = find_task_node(&sched_cb.suspended_tasks, &*this_task)
+                .expect("Suspended task not found in suspended list!");
 
-                assert!(task_l.is_some());
                 let task_node = unsafe {
-                    ListNode::into_inner(sched_cb.active_tasks.remove_node(task_l.unwrap()))
+                    ListNode::into_inner(sched_cb.suspended_tasks.remove_node(task_node))
                 };
 
                 sched_cb.terminated_tasks.insert_node_at_tail(task_node);
@@ -514,7 +1121,9 @@ pub fn kill_thread(task_id: usize) {
                 yield_flag = hal::get_core() == core;
             }
         }
-    }
+
+        core
+    };
 
     if !skip_notify {
         notify_other_cpu(core);
@@ -522,23 +1131,11 @@ pub fn kill_thread(task_id: usize) {
 
     // Inform semaphore that this task is about to be killed, remove it from the blocked list
     if drop_task {
-        while this_task.lock().wait_semaphores.get_nodes() != 0 {
-            // Right now, there is no option for a task to be in multiple wait lists
-            // So this should be valid
-            assert!(this_task.lock().wait_semaphores.get_nodes() == 1);
-            // We do it in this weird fashion since we don't want the task to be locked during call to drop_task
-            let (sem_wrap, sem_inner) = {
-                let task = this_task.lock();
-                let sem = task.wait_semaphores.first();
-                (Arc::clone(&**sem.unwrap()), NonNull::from(sem.unwrap()))
-            };
-
-            KSem::drop_task(sem_wrap, task_id);
-
-            unsafe {
-                this_task.lock().wait_semaphores.remove_node(sem_inner);
-            }
-        } 
+        // We do it in this fashion since we don't want the task to be locked during call to drop_task
+        let wait_semaphores = take(&mut this_task.lock().wait_semaphores);
+        for sem in wait_semaphores.iter() {
+            KSem::drop_task(Arc::clone(sem), task_id);
+        }
     }
 
     // Drop it explicitly since we won't return from here and rust thinks that 
@@ -558,13 +1155,107 @@ pub fn kill_thread(task_id: usize) {
 }
 
 pub fn exit_thread() -> ! {
+    exit_thread_with(0);
+}
+
+// Exit code is handed to whoever joins this thread
+pub fn exit_thread_with(code: i64) -> ! {
     let thread_id = get_current_task_id().expect("Attempted to kill idle task!!");
 
     assert!(is_preemption_enabled(), "exit_thread() called with preemption disabled!");
 
-    kill_thread(thread_id);
+    kill_thread_do_work(thread_id, Some(code));
+
+    panic!("exit_thread() unreachable reached!!");
+}
+
+// Task stops running at the latest on the next reschedule of it's core
+// Suspend requests nest, so every suspend_thread needs a matching resume_thread
+pub fn suspend_thread(task_id: usize) -> Result<(), KError> {
+    let this_task = get_task_info(task_id).ok_or(KError::InvalidArgument)?;
+
+    disable_preemption();
+    let res = {
+        let (mut sched_cb, core) = lock_task_scheduler(&this_task);
+        let mut task = this_task.lock();
+        let status = task.status;
+
+        if status == TaskStatus::TERMINATED {
+            Err(KError::InvalidArgument)
+        }
+        else {
+            task.suspend_count += 1;
+
+            // A queued task can be taken off the run queue right away
+            // Running and waiting tasks move over once they're switched out or woken up
+            if status == TaskStatus::ACTIVE {
+                let task_node = sched_cb.remove_active(&*this_task, task.priority);
+                sched_cb.suspended_tasks.insert_node_at_tail(task_node);
+                task.status = TaskStatus::SUSPENDED;
+            }
+
+            Ok((core, status))
+        }
+    };
+
+    let is_self = match res {
+        Ok((core, TaskStatus::RUNNING)) => {
+            notify_other_cpu(core);
+            get_current_task_id() == Some(task_id)
+        },
+        _ => false
+    };
+
+    drop(this_task);
+    enable_preemption();
+
+    // We suspended ourselves, so give up the cpu right away
+    if is_self {
+        yield_cpu();
+    }
+
+    res.map(|_| ())
+}
+
+pub fn resume_thread(task_id: usize) -> Result<(), KError> {
+    let this_task = get_task_info(task_id).ok_or(KError::InvalidArgument)?;
+
+    disable_preemption();
+    let res = {
+        let (mut sched_cb, core) = lock_task_scheduler(&this_task);
+        let mut task = this_task.lock();
+
+        if task.suspend_count == 0 {
+            Err(KError::InvalidArgument)
+        }
+        else {
+            task.suspend_count -= 1;
+
+            // Last resume puts the task back in the run queue. If it was still running or waiting, clearing
+            // the count is enough, since the suspension hadn't taken effect yet
+            if task.suspend_count == 0 && task.status == TaskStatus::SUSPENDED {
+                let task_node = find_task_node(&sched_cb.suspended_tasks, &*this_task)
+                .expect("Suspended task not found in suspended list!");
+
+                let task_node = unsafe {
+                    ListNode::into_inner(sched_cb.suspended_tasks.remove_node(task_node))
+                };
+
+                sched_cb.queue_active(task_node, &task, false);
+                task.status = TaskStatus::ACTIVE;
+            }
+
+            Ok(core)
+        }
+    };
+
+    if let Ok(core) = res {
+        notify_other_cpu(core);
+    }
+
+    enable_preemption();
 
-    panic!("exit_thread() unreachable reached!!");
+    res.map(|_| ())
 }
 
 // We do all this moving out of stuff and into other stuff drama in order to avoid holding any lock during signal operation
@@ -575,63 +1266,97 @@ fn reap_tasks(sched_cb: &mut TaskQueue) {
             &*task.as_ptr()
         };
         
-        let id = task_inner.lock().get_id();
+        let (id, name) = {
+            let task = task_inner.lock();
+            (task.get_id(), task.name.clone())
+        };
 
         sched_cb.notifier_list.add_node(task_inner.lock().term_notify.clone()).expect("Failed to add semaphore to notifier list!");
 
         // Extract the pointer, release the lock and then call remove_thread
         // Otherwise, we run the risk of deadlock
         {
-            let process_ref = task_inner.lock().process.as_ref().unwrap().clone();
+            let (process_ref, stats, exit_code) = {
+                let mut task = task_inner.lock();
+
+                // Hand back the reserved bandwidth
+                if let Some(deadline) = task.deadline.take() {
+                    sched_cb.deadline_util -= deadline.params.get_utilization();
+                }
+
+                (task.process.as_ref().unwrap().clone(), task.stats, task.exit_code)
+            };
+
             let mut process_guard = process_ref.lock();
+            process_guard.add_exited_thread_stats(&stats);
+            let was_killed = process_guard.get_status() == ProcessStatus::Terminated;
             let is_last_thread_in_proc = process_guard.remove_thread(id);
             
             if is_last_thread_in_proc {
+                // Process wasn't killed as a whole, but went away because all of it's threads exited
+                // It then reports the exit code of it's last thread
+                if !was_killed {
+                    process_guard.set_exit_code(exit_code);
+                }
+
                 info!("Adding process {} notifier to notifier list as task {} is terminating", process_guard.get_id(), id);
                 sched_cb.notifier_list.add_node(process_guard.get_notify_sem()).expect("Failed to add process notify semaphore to notifier list!");
                 sched_cb.notifier_list.add_node(process_guard.get_init_sem()).expect("Failed to add process init semaphore to notifier list!");
+                drop(process_guard);
+
+                if let Some(parent_notify) = on_process_exit(&process_ref) {
+                    sched_cb.notifier_list.add_node(parent_notify).expect("Failed to add parent notify semaphore to notifier list!");
+                }
             }
         }
 
-        info!("Removing task {} on core {}", id, hal::get_core());
+        info!("Removing task {} ({}) on core {}", id, name.as_deref().unwrap_or(UNNAMED), hal::get_core());
         unsafe {
             sched_cb.terminated_tasks.remove_node(task);
         }
         
-        TASKS.lock().remove(&id);
+        TASKS.write().remove(&id);
     }   
 }
 
 fn update_timers(sched_cb: &mut TaskQueue) {
-    let mut idx = 0;
-    let list_size = sched_cb.timer_list.get_nodes();
+    let now = hal::get_time_ms();
+
+    // List is sorted by deadline, so stop at the first timer that hasn't expired
+    while let Some(timer) = sched_cb.timer_list.first() {
+        if !timer.lock().check_expiry(now) {
+            break;
+        }
 
-    while idx < list_size {
-        let timer = sched_cb.timer_list.first().unwrap();
+        let sem = timer.lock().get_semaphore();
+        sched_cb.notifier_list.add_node(sem).expect("Unable to add timer node semaphore into notifier list!");
 
-        let is_done = timer.lock().update_timer_count(QUANTUM);
+        unsafe {
+            sched_cb.timer_list.remove_node(NonNull::from(timer))
+        };
+    }
+}
 
-        if is_done {
-            let sem = timer.lock().get_semaphore();
-            sched_cb.notifier_list.add_node(sem).expect("Unable to add timer node semaphore into notifier list!");
 
-            unsafe {
-                sched_cb.timer_list.remove_node(NonNull::from(timer))
-            };
-        }
-        else {
-            let timer_ref = unsafe {
-                ListNode::into_inner(sched_cb.timer_list.remove_node(NonNull::from(timer)))
-            };
-            
-            sched_cb.timer_list.insert_node_at_tail(timer_ref);
+// Charges the running deadline task and starts new periods for the queued ones
+fn update_deadline_tasks(sched_cb: &mut TaskQueue) {
+    let now = hal::get_time_ms();
+
+    if let Some(task) = sched_cb.running_task {
+        let mut task = unsafe { task.as_ref() }.lock();
+        if let Some(deadline) = task.deadline.as_mut() {
+            deadline.charge(now);
+            deadline.replenish(now);
         }
+    }
 
-        idx += 1;
+    for task in sched_cb.active_tasks[TaskPriority::Deadline as usize].iter() {
+        if let Some(deadline) = task.lock().deadline.as_mut() {
+            deadline.replenish(now);
+        }
     }
 }
 
-
 fn notify_watchers(notifier_list: &DynList<KSem>) {
     for sem in notifier_list.iter() {
         sem.signal();
@@ -649,8 +1374,196 @@ pub fn enable_preemption() {
     sched_cb.preemption_count = sched_cb.preemption_count.saturating_sub(1);
 }
 
-fn can_sleep(sched_cb: &mut TaskQueue) -> bool {
-    sched_cb.flip_flop == false && sched_cb.timer_list.get_nodes() == 0
+// Put the task leaving the cpu into the list matching it's status
+fn queue_switched_out_task(sched_cb: &mut TaskQueue, task_info: &mut Task, task_node: NonNull<ListNode<KThread>>) {
+    // A pending suspend request takes effect here
+    if task_info.status == TaskStatus::RUNNING {
+        task_info.status = if task_info.suspend_count > 0 {
+            TaskStatus::SUSPENDED
+        }
+        else {
+            TaskStatus::ACTIVE
+        };
+    }
+
+    match task_info.status {
+        TaskStatus::WAITING => sched_cb.waiting_tasks.insert_node_at_tail(task_node),
+        TaskStatus::SUSPENDED => sched_cb.suspended_tasks.insert_node_at_tail(task_node),
+        TaskStatus::TERMINATED => {
+            debug!("Adding task {} to terminated list", task_info.id);
+            sched_cb.terminated_tasks.insert_node_at_tail(task_node);
+        },
+        _ => sched_cb.queue_active(task_node, task_info, false)
+    }
+}
+
+// Dynamic ticks. The one-shot timer is only armed for when this core actually needs the next tick
+fn program_next_tick(sched_cb: &TaskQueue) {
+    // Other tasks want this cpu (or are parked here waiting to be pushed to another core), or a stack deletion is pending
+    if sched_cb.flip_flop || sched_cb.misplaced_tasks.get_nodes() != 0 || sched_cb.active_tasks.iter().any(|queue| queue.get_nodes() != 0) {
+        enable_scheduler_timer();
+        return;
+    }
+
+    // Nearest timer expiry, rounded up to whole ticks
+    let now = hal::get_time_ms();
+    let timer_ticks = sched_cb.timer_list.first().map(|timer| {
+        timer.lock().get_deadline().saturating_sub(now).div_ceil(QUANTUM).max(1)
+    });
+
+    if let Some(task) = sched_cb.running_task {
+        // A deadline task has to be looked at again once it's budget runs out
+        let budget_ticks = unsafe { task.as_ref() }.lock().deadline.as_ref().map(|deadline| {
+            deadline.budget.div_ceil(QUANTUM).max(1)
+        });
+
+        // Single task on this core, there's nothing to rotate it with
+        // We still need to wake up now and then for load balancing
+        let ticks = [timer_ticks, budget_ticks].into_iter().flatten().fold(BALANCE_INTERVAL, usize::min);
+        enable_scheduler_timer_for(ticks);
+    }
+    else if let Some(ticks) = timer_ticks {
+        enable_scheduler_timer_for(ticks);
+    }
+    else {
+        // Nothing to do until some other core or an interrupt hands us work
+        disable_scheduler_timer();
+    }
+}
+
+// Hand over a task node (already removed from it's old run queue) to dest
+// Caller must hold the scheduler locks of both cores
+fn move_task_node(task_node: NonNull<ListNode<KThread>>, dest: &mut TaskQueue, dest_core: usize) {
+    let priority = {
+        let mut task = unsafe { task_node.as_ref() }.lock();
+        assert!(task.status == TaskStatus::ACTIVE);
+
+        debug!("Migrating task {} ({}) from core {} to core {}", task.id, task.get_name().unwrap_or(UNNAMED), task.core, dest_core);
+        task.core = dest_core;
+
+        // A task switched out in kernel mode carries the per cpu base of it's old core
+        // In user mode, it's the user gs base which stays as it is
+        #[cfg(target_arch = "x86_64")]
+        if task.is_kernel_mode {
+            task.per_cpu_base = get_per_cpu_kernel_base_for_core(dest_core);
+        }
+
+        task.priority
+    };
+
+    dest.run_queue(priority).insert_node_at_tail(task_node);
+}
+
+// Move one queued task from src to dest. Caller must hold the scheduler locks of both cores
+fn migrate_task(src: &mut TaskQueue, dest: &mut TaskQueue, dest_core: usize) -> bool {
+    // Take from the highest class that has a task which can run on dest. Prefer the one nearest to the tail, 
+    // as it would have waited the longest on src
+    // The task src just switched out can't be taken, since src might still be running on it's stack
+    let candidate = src.active_tasks.iter().enumerate().find_map(|(priority, queue)| {
+        let task = queue.iter().filter(|task| {
+            !src.switched_out.is_some_and(|switched_out| core::ptr::eq(Arc::as_ptr(&***task), switched_out)) &&
+            task.lock().affinity.contains(dest_core)
+        }).last()?;
+
+        Some((priority, NonNull::from(task)))
+    });
+
+    let Some((priority, task_node)) = candidate else {
+        return false;
+    };
+
+    let task_node = unsafe {
+        ListNode::into_inner(src.active_tasks[priority].remove_node(task_node))
+    };
+
+    move_task_node(task_node, dest, dest_core);
+    true
+}
+
+// Tasks parked in misplaced_tasks are handed over to the least loaded core they're allowed on
+// If no such core can be locked right now, the task stays parked (without being run) until the next attempt
+fn push_misplaced_tasks(sched_cb: &mut TaskQueue, notify_cores: &mut CoreMask) {
+    // Go through the list once, so that the order of the remaining tasks is preserved
+    for _ in 0..sched_cb.misplaced_tasks.get_nodes() {
+        let task_node = NonNull::from(sched_cb.misplaced_tasks.first().unwrap());
+        let task_node = unsafe {
+            ListNode::into_inner(sched_cb.misplaced_tasks.remove_node(task_node))
+        };
+
+        let affinity = unsafe { task_node.as_ref() }.lock().affinity;
+        let mut target: Option<(usize, SpinlockGuard<'static, TaskQueue>)> = None;
+
+        for core in affinity.cores() {
+            // Same as in balance_load, never spin on another scheduler lock while holding our own
+            let Some(remote) = (unsafe { SCHEDULER_CON_BLK.get(core).try_lock() }) else {
+                continue;
+            };
+
+            if target.as_ref().is_none_or(|(_, best)| remote.get_load() < best.get_load()) {
+                target = Some((core, remote));
+            }
+        }
+
+        if let Some((core, mut remote)) = target {
+            move_task_node(task_node, &mut remote, core);
+            notify_cores.add(core);
+        }
+        else {
+            sched_cb.misplaced_tasks.insert_node_at_tail(task_node);
+        }
+    }
+}
+
+// Pull a task from the busiest core every BALANCE_INTERVAL, or right away if this core is about to go idle
+// An idle core has it's timer switched off and won't come looking for work on it's own, so it's added to notify_cores
+fn balance_load(sched_cb: &mut TaskQueue, notify_cores: &mut CoreMask) {
+    let is_busy = sched_cb.next_active_task().is_some() || sched_cb.running_task.is_some_and(|task| unsafe {
+        task.as_ref().lock().status == TaskStatus::RUNNING
+    });
+
+    let now = hal::get_time_ms();
+    if is_busy && now.saturating_sub(sched_cb.last_balance_time) < BALANCE_INTERVAL * QUANTUM {
+        return;
+    }
+
+    sched_cb.last_balance_time = now;
+
+    let this_core = hal::get_core();
+    let load = sched_cb.get_load();
+    let mut busiest: Option<(usize, usize)> = None;
+    let mut idle_core = None;
+
+    for core in 0..get_total_cores() {
+        if core == this_core {
+            continue;
+        }
+
+        // We never spin on another scheduler lock while holding our own
+        // Two cores balancing against each other would deadlock otherwise
+        let Some(remote) = (unsafe { SCHEDULER_CON_BLK.get(core).try_lock() }) else {
+            continue;
+        };
+
+        let remote_load = remote.get_load();
+        if remote_load == 0 {
+            idle_core = Some(core);
+        }
+
+        // Only worth moving a task if it actually reduces the imbalance
+        if remote_load > load + 1 && busiest.is_none_or(|(_, busiest_load)| remote_load > busiest_load) {
+            busiest = Some((core, remote_load));
+        }
+    }
+
+    if let Some((core, _)) = busiest {
+        if let Some(mut remote) = unsafe { SCHEDULER_CON_BLK.get(core).try_lock() } {
+            migrate_task(&mut remote, sched_cb, this_core);
+        }
+    }
+
+    if let Some(core) = idle_core.filter(|_| load > 1) {
+        notify_cores.add(core);
+    }
 }
 
 #[inline]
@@ -721,14 +1634,24 @@ fn setup_current_task_ptr(sched_cb: &mut TaskQueue) {
 
 // Main scheduler loop
 pub fn schedule() {
+    let mut notify_cores = CoreMask::empty();
     let notifier_list = {
         let mut sched_cb = SCHEDULER_CON_BLK.local().lock();
         update_timers(&mut sched_cb);
+        update_deadline_tasks(&mut sched_cb);
+
+        // We're on a different stack now compared to the last schedule call
+        sched_cb.switched_out = None;
         
         if sched_cb.preemption_count > 0 {
+            // Keep ticking, so that we get back here once preemption is enabled again
+            enable_scheduler_timer();
             take(&mut sched_cb.notifier_list)
         }
         else {
+            push_misplaced_tasks(&mut sched_cb, &mut notify_cores);
+            balance_load(&mut sched_cb, &mut notify_cores);
+
             if sched_cb.running_task.is_some() {
                 let current_task = sched_cb.running_task.unwrap(); 
 
@@ -739,13 +1662,36 @@ pub fn schedule() {
                 task_info.quanta = task_info.quanta.saturating_sub(1);
                 let old_vcb = task_info.vcb.expect("VCB is none");
 
+                // First choose new task
+                // We create NonNull here so that the node can later be removed
+                let head_task = sched_cb.next_active_task();
+                let (head_priority, head_deadline) = head_task.map(|item| {
+                    let head_task_info = unsafe { item.as_ref() }.lock();
+                    (head_task_info.priority, head_task_info.get_abs_deadline())
+                }).unzip();
+
+                // A task of higher class (or a deadline task with an earlier deadline) became runnable, so preempt the current one
+                let earlier_deadline = matches!((head_deadline.flatten(), task_info.get_abs_deadline()), 
+                    (Some(head_deadline), Some(cur_deadline)) if head_deadline < cur_deadline);
+                let preempt = earlier_deadline || head_priority.is_some_and(|priority| priority.is_higher_than(task_info.priority));
+
+                // Affinity of the current task was changed to exclude this core. It's switched out here and handed over
+                // to an allowed core by push_misplaced_tasks on the next schedule call (once we're off it's stack)
+                let misplaced = !task_info.affinity.contains(hal::get_core());
+
+                // Task has a pending suspend request
+                let suspend = task_info.status == TaskStatus::RUNNING && task_info.suspend_count > 0;
+
+                // Deadline task used up it's budget for this period
+                let throttled = task_info.is_throttled();
+                let must_leave = misplaced || suspend || throttled;
+
                 // Switch to new task
                 if task_info.status == TaskStatus::WAITING || task_info.status == TaskStatus::TERMINATED ||
-                task_info.quanta == 0 {
-                    // First choose new task
-                    // We create NonNull here so that the node can later be removed
-                    let head_task = sched_cb.active_tasks.first().and_then(|item| {
-                        Some(NonNull::from(item))
+                task_info.quanta == 0 || preempt || must_leave {
+                    // A running task keeps the cpu if only lower class tasks are pending
+                    let head_task = head_task.filter(|_| {
+                        task_info.status != TaskStatus::RUNNING || must_leave || !task_info.priority.is_higher_than(head_priority.unwrap())
                     });
 
                     if head_task.is_some() {
@@ -755,10 +1701,15 @@ pub fn schedule() {
                         
                         assert!(head_task_info.status == TaskStatus::ACTIVE); 
                         head_task_info.status = TaskStatus::RUNNING;
-                        head_task_info.quanta = INIT_QUANTA;
+                        head_task_info.quanta = head_task_info.priority.get_quanta();
+                        head_task_info.restart_budget_clock();
                         let new_context = head_task_info.context;
                         let new_vcb = head_task_info.vcb.expect("VCB is none");
 
+                        let now = hal::read_timestamp();
+                        task_info.account_switch_out(now);
+                        head_task_info.last_timestamp = now;
+
                         let prev_context = fetch_context();
                         task_info.context = prev_context;
 
@@ -767,24 +1718,14 @@ pub fn schedule() {
                             task_info.per_cpu_base = get_per_cpu_base();
                         }
 
-                        if task_info.status == TaskStatus::RUNNING {
-                            task_info.status = TaskStatus::ACTIVE; 
-                        }
-
                         // This ensures that list doesn't delete the node. It simply removes it from the list 
                         let head_task = unsafe {
-                            ListNode::into_inner(sched_cb.active_tasks.remove_node(head_task.unwrap()))
+                            ListNode::into_inner(sched_cb.run_queue(head_task_info.priority).remove_node(head_task.unwrap()))
                         };
 
-                        if task_info.status == TaskStatus::WAITING {
-                            sched_cb.waiting_tasks.insert_node_at_tail(current_task);
-                        }
-                        else if task_info.status == TaskStatus::TERMINATED {
-                            sched_cb.terminated_tasks.insert_node_at_tail(current_task);
-                        }
-                        else {
-                            sched_cb.active_tasks.insert_node_at_tail(current_task);
-                        }
+                        // Even a waiting task could be woken up and put back in the run queue before we leave this stack
+                        sched_cb.switched_out = Some(Arc::as_ptr(unsafe { &**current_task.as_ref() }));
+                        queue_switched_out_task(&mut sched_cb, &mut task_info, current_task);
 
                         sched_cb.running_task = Some(head_task);
 
@@ -796,37 +1737,31 @@ pub fn schedule() {
                         set_per_cpu_base(head_task_info.per_cpu_base);
                     }
                     else {
-                        if task_info.status != TaskStatus::RUNNING {
+                        if task_info.status != TaskStatus::RUNNING || must_leave {
                             let prev_context = fetch_context();
                             task_info.context = prev_context;
+                            task_info.account_switch_out(hal::read_timestamp());
                             
                             #[cfg(target_arch = "x86_64")] 
                             {
                                 task_info.per_cpu_base = get_per_cpu_base();
                             }
 
-                            if task_info.status == TaskStatus::WAITING {
-                                sched_cb.waiting_tasks.insert_node_at_tail(current_task);
-                            }
-                            else if task_info.status == TaskStatus::TERMINATED {
-                                debug!("Adding task {} to terminated list", task_info.id);
-                                sched_cb.terminated_tasks.insert_node_at_tail(current_task);
-                            }
+                            sched_cb.switched_out = Some(Arc::as_ptr(unsafe { &**current_task.as_ref() }));
+                            queue_switched_out_task(&mut sched_cb, &mut task_info, current_task);
 
                             prep_idle_task(&mut sched_cb, old_vcb);
                         }
                         else {
                             // No other task to run. Continue with this task
-                            task_info.quanta = INIT_QUANTA;
+                            task_info.quanta = task_info.priority.get_quanta();
                         }
                     }
                 }
             }
             else {
                 // This means we're in idle task. Check and run any active tasks
-                let head_task = sched_cb.active_tasks.first().and_then(|item| {
-                    Some(NonNull::from(item))
-                });
+                let head_task = sched_cb.next_active_task();
 
                 if head_task.is_some() {
                     let mut head_task_info = unsafe {
@@ -835,11 +1770,16 @@ pub fn schedule() {
 
                     assert!(head_task_info.status == TaskStatus::ACTIVE); 
                     head_task_info.status = TaskStatus::RUNNING;
-                    head_task_info.quanta = INIT_QUANTA;
+                    head_task_info.quanta = head_task_info.priority.get_quanta();
+                    head_task_info.restart_budget_clock();
+
+                    let now = hal::read_timestamp();
+                    sched_cb.idle_time += now.saturating_sub(sched_cb.idle_stamp);
+                    head_task_info.last_timestamp = now;
                     let new_context = head_task_info.context;
                     
                     let head_task = unsafe {
-                        ListNode::into_inner(sched_cb.active_tasks.remove_node(head_task.unwrap()))
+                        ListNode::into_inner(sched_cb.run_queue(head_task_info.priority).remove_node(head_task.unwrap()))
                     };
                     sched_cb.running_task = Some(head_task);
                     
@@ -853,12 +1793,6 @@ pub fn schedule() {
                     #[cfg(target_arch = "x86_64")]
                     set_per_cpu_base(head_task_info.per_cpu_base);
                 }
-                else {
-                    // If stack deletions / timers are pending, don't go into idle task yet
-                    if can_sleep(&mut sched_cb) {
-                        disable_scheduler_timer(); 
-                    }
-                }
             }
 
             setup_current_task_ptr(&mut sched_cb);
@@ -873,15 +1807,21 @@ pub fn schedule() {
             }
 
             reap_tasks(&mut sched_cb);
+            program_next_tick(&sched_cb);
             take(&mut sched_cb.notifier_list)
         }
     };
 
     notify_watchers(&notifier_list);
+
+    for core in notify_cores.cores() {
+        notify_other_cpu(core);
+    }
 }
 
 fn prep_idle_task(sched_cb: &mut TaskQueue, old_vcb: VCB) {
     sched_cb.running_task = None;
+    sched_cb.idle_stamp = hal::read_timestamp();
     let context = create_kernel_context(idle_task, sched_cb.idle_task_stack.as_ptr() as *mut u8);
     
     switch_address_space_for_idle(old_vcb);
@@ -890,14 +1830,10 @@ fn prep_idle_task(sched_cb: &mut TaskQueue, old_vcb: VCB) {
 
     #[cfg(target_arch = "x86_64")]
     set_per_cpu_base(get_per_cpu_kernel_base());
-    
-    if can_sleep(sched_cb) {
-        disable_scheduler_timer(); 
-    }
 }
 
 fn idle_task() -> ! {
-    hal::sleep();
+    super::idle::idle_loop();
 }
 
 fn notify_other_cpu(target_core: usize) {
@@ -910,10 +1846,22 @@ fn notify_other_cpu(target_core: usize) {
     hal::notify_core(IPIRequestType::SchedChange, target_core);
 }
 
-fn create_thread_common(handler: fn() -> !, user_function: Option<fn() -> !>) -> Result<(KThread, usize), KError> {
+fn create_thread_common(handler: fn() -> !, user_function: Option<fn() -> !>, priority: TaskPriority, affinity: CoreMask,
+    name: Option<&str>) -> Result<(KThread, usize), KError> {
+    // Threads start off in a regular class and move over with set_deadline()
+    if priority == TaskPriority::Deadline {
+        return Err(KError::InvalidArgument);
+    }
+
     // We will use simple round robin to determine the cpu which gets this task
-    let core = TASK_CPU.fetch_add(1, Ordering::Relaxed) as usize % get_total_cores();   
-    let task = Task::new(true, core, user_function)?;
+    // Cores outside the affinity mask are skipped
+    let total_cores = get_total_cores();
+    let start_core = TASK_CPU.fetch_add(1, Ordering::Relaxed) as usize % total_cores;
+    let core = (0..total_cores).map(|offset| (start_core + offset) % total_cores)
+    .find(|core| affinity.contains(*core))
+    .ok_or(KError::InvalidArgument)?;
+
+    let task = Task::new(true, core, user_function, priority, affinity, name)?;
     
     {
         let mut task = task.lock();
@@ -929,15 +1877,18 @@ fn create_thread_common(handler: fn() -> !, user_function: Option<fn() -> !>) ->
 }
 
 // Internal API: Do not call this
-pub fn create_init_thread(handler: fn() -> !, process: KProcess) -> Result<KThread, KError> {
-    let is_user_thread = process.lock().get_user_flag();
-    let proc_id = process.lock().get_id();
+// Init thread goes by the name of it's process
+pub fn create_init_thread(handler: fn() -> !, process: KProcess, priority: TaskPriority) -> Result<KThread, KError> {
+    let (is_user_thread, proc_id, name) = {
+        let guard = process.lock();
+        (guard.get_user_flag(), guard.get_id(), guard.get_name().map(String::from))
+    };
 
     let (thread, core) = if is_user_thread {
-        create_thread_common(super::user::user_init_handler, Some(handler))?
+        create_thread_common(super::user::user_init_handler, Some(handler), priority, CoreMask::all(), name.as_deref())?
     }
     else {
-        create_thread_common(handler, None)?
+        create_thread_common(handler, None, priority, CoreMask::all(), name.as_deref())?
     };
 
     let thread_id = thread.lock().get_id();
@@ -949,32 +1900,72 @@ pub fn create_init_thread(handler: fn() -> !, process: KProcess) -> Result<KThre
     Ok(thread)
 }
 
-pub fn start_task(thread: &KThread, core: usize, process: &KProcess, registry: &Spinlock<BTreeMap<usize, KProcess>>) -> Result<(), KError> {
+// Internal API: Do not call this
+// Thread of a forked process. It starts off in user mode, right where the parent entered the fork syscall
+pub fn create_forked_thread(process: KProcess, priority: TaskPriority, context: UserContext) -> Result<KThread, KError> {
+    let (proc_id, affinity, proc_addr_space, name) = {
+        let guard = process.lock();
+        (guard.get_id(), guard.get_affinity(), guard.get_vcb(), guard.get_name().map(String::from))
+    };
+
+    let (thread, core) = create_thread_common(super::user::fork_return_handler, Some(super::user::fork_return_handler), priority, affinity,
+        name.as_deref())?;
+
+    let thread_id = {
+        let mut guard = thread.lock();
+        guard.process = Some(process);
+        guard.vcb = Some(proc_addr_space);
+        guard.resume_context = Some(context);
+        guard.id
+    };
+
+    debug!("Created forked thread {} on process {} on core {}", thread_id, proc_id, core);
+    Ok(thread)
+}
+
+pub fn start_task(thread: &KThread, core: usize, process: &KProcess, registry: &RwSpinlock<BTreeMap<usize, KProcess>>) -> Result<(), KError> {
     {
         let mut sched_cb = unsafe {
             SCHEDULER_CON_BLK.get(core).lock()
         };
         
-        let thread_id = thread.lock().get_id();
-        // Add to ready queue
-        sched_cb.active_tasks.add_node(Arc::clone(&thread))?;
+        let (thread_id, priority) = {
+            let guard = thread.lock();
+            (guard.get_id(), guard.priority)
+        };
 
+        // Thread only becomes runnable once the process has taken it
         let mut process_inner = process.lock();
         let proc_id = process_inner.get_id();
         process_inner.attach_thread_to_current_process(thread_id)?;
-        registry.lock().insert(proc_id, Arc::clone(&process));
 
-        TASKS.lock().insert(thread_id, Arc::clone(&thread));
+        // Add to ready queue
+        if let Err(e) = sched_cb.run_queue(priority).add_node(Arc::clone(&thread)) {
+            process_inner.remove_thread(thread_id);
+            return Err(e);
+        }
+
+        registry.write().insert(proc_id, Arc::clone(&process));
+
+        TASKS.write().insert(thread_id, Arc::clone(&thread));
     }
     notify_other_cpu(core);
 
     Ok(())
 }
 
-pub fn create_thread_do_work(handler: fn() -> !, user_fn: Option<fn() -> !>) -> Result<KThread, KError> {
+// Thread inherits the affinity of it's process if none is given
+// If an entry closure is given, handler must be closure_trampoline
+pub fn create_thread_do_work(handler: fn() -> !, user_fn: Option<fn() -> !>, priority: TaskPriority, affinity: Option<CoreMask>,
+    entry: Option<ThreadEntry>, name: Option<&str>) -> Result<KThread, KError> {
     disable_preemption();
 
-    let (thread, core) = match create_thread_common(handler, user_fn) {
+    let cur_process = get_current_process();
+    let affinity = affinity.unwrap_or_else(|| {
+        cur_process.as_ref().map_or(CoreMask::all(), |process| process.lock().get_affinity())
+    });
+
+    let (thread, core) = match create_thread_common(handler, user_fn, priority, affinity, name) {
         Ok(v) => v,
         Err(e) => {
             enable_preemption();
@@ -982,8 +1973,11 @@ pub fn create_thread_do_work(handler: fn() -> !, user_fn: Option<fn() -> !>) ->
         }
     };
 
-    let thread_id = thread.lock().get_id();
-    let cur_process = get_current_process();
+    let thread_id = {
+        let mut guard = thread.lock();
+        guard.entry = entry;
+        guard.get_id()
+    };
 
     // Lock order => Scheduler -> Process -> Task
     // We compute the setup result inside this block so that all the locks
@@ -1008,17 +2002,22 @@ pub fn create_thread_do_work(handler: fn() -> !, user_fn: Option<fn() -> !>) ->
                 Err(e)
             }
             else {
-                debug!("Creating new task {} under process {}", thread_id, guard.get_id());
+                debug!("Creating new task {} under process {} ({})", thread_id, guard.get_id(), guard.get_name().unwrap_or(UNNAMED));
                 thread.lock().process = Some(process_ref);
                 thread.lock().vcb = Some(proc_addr_space);
                 drop(guard);
 
-                match sched_cb.active_tasks.add_node(Arc::clone(&thread)) {
+                match sched_cb.run_queue(priority).add_node(Arc::clone(&thread)) {
                     Ok(_) => {
-                        TASKS.lock().insert(thread_id, Arc::clone(&thread));
+                        TASKS.write().insert(thread_id, Arc::clone(&thread));
                         Ok(())
                     }
-                    Err(e) => Err(e),
+                    Err(e) => {
+                        // Thread never ran, so take it off the process again. Otherwise the process waits on it forever
+                        // The calling thread still belongs to the process, so this can't be the last one
+                        process.lock().remove_thread(thread_id);
+                        Err(e)
+                    }
                 }
             }
         }
@@ -1039,8 +2038,31 @@ pub fn create_thread_do_work(handler: fn() -> !, user_fn: Option<fn() -> !>) ->
 }
 
 // Must be called from valid process context 
-pub fn create_thread(handler: fn() -> !) -> Result<KThread, KError> {
-    let res = create_thread_do_work(handler, None);
+// Thread is created in the Normal class if no priority is given
+pub fn create_thread(handler: fn() -> !, priority: Option<TaskPriority>) -> Result<KThread, KError> {
+    let res = create_thread_do_work(handler, None, priority.unwrap_or_default(), None, None, None);
+    if res.is_err() {
+        info!("Failed to create kernel thread");
+    }
+
+    res
+}
+
+// Same as create_thread, with the name given right from the start
+pub fn create_named_thread(handler: fn() -> !, priority: Option<TaskPriority>, name: &str) -> Result<KThread, KError> {
+    let res = create_thread_do_work(handler, None, priority.unwrap_or_default(), None, None, Some(name));
+    if res.is_err() {
+        info!("Failed to create kernel thread {}", name);
+    }
+
+    res
+}
+
+// Kernel thread running a closure, so that it can carry it's own context instead of going through statics
+// Thread exits once the closure returns
+pub fn create_thread_with<F: FnOnce() + Send + 'static>(entry: F, priority: Option<TaskPriority>) -> Result<KThread, KError> {
+    let res = create_thread_do_work(closure_trampoline, None, priority.unwrap_or_default(), None, 
+        Some(Box::new_in(entry, PoolAllocatorGlobal)), None);
     if res.is_err() {
         info!("Failed to create kernel thread");
     }
@@ -1048,7 +2070,48 @@ pub fn create_thread(handler: fn() -> !) -> Result<KThread, KError> {
     res
 }
 
+// For FFI callers which pass their context as a raw argument
+pub fn create_thread_with_arg(handler: extern "C" fn(usize), arg: usize, priority: Option<TaskPriority>) -> Result<KThread, KError> {
+    create_thread_with(move || handler(arg), priority)
+}
+
+fn closure_trampoline() -> ! {
+    // Keep the task reference in it's own scope, since we never return from exit_thread
+    let entry = {
+        let task = get_current_task().expect("closure_trampoline() called from idle task!");
+        let entry = task.lock().entry.take();
+        entry.expect("Thread started without an entry closure!")
+    };
+
+    entry();
+
+    exit_thread();
+}
+
+// Same as create_thread, but the thread only ever runs on the cores in the affinity mask
+pub fn create_thread_with_affinity(handler: fn() -> !, priority: Option<TaskPriority>, affinity: CoreMask) -> Result<KThread, KError> {
+    if !affinity.is_valid() {
+        return Err(KError::InvalidArgument);
+    }
+
+    let res = create_thread_do_work(handler, None, priority.unwrap_or_default(), Some(affinity), None, None);
+    if res.is_err() {
+        info!("Failed to create kernel thread with affinity {:#X}", affinity.get_bits());
+    }
+
+    res
+}
+
 impl Spinlock<Task> {
+    pub fn get_name(&self) -> Option<String> {
+        self.lock().name.clone()
+    }
+
+    pub fn set_name(&self, name: &str) {
+        let name = String::from(name);
+        self.lock().name = Some(name);
+    }
+
     pub fn wait(&self) -> Result<(), KError> {
         let sem = {
             let task = self.lock();
@@ -1057,4 +2120,198 @@ impl Spinlock<Task> {
 
         sem.wait()
     }
+
+    // Waits for the thread to go away and returns it's exit code, or KError::Killed if it was killed
+    pub fn join(&self) -> Result<i64, KError> {
+        // Termination signal may already have been taken by a wait() or an earlier joiner, so don't block on it then
+        // Exit code doesn't change anymore once the thread is terminated
+        {
+            let task = self.lock();
+            if task.status == TaskStatus::TERMINATED {
+                return task.exit_code.ok_or(KError::Killed);
+            }
+        }
+
+        self.wait()?;
+
+        // Pass the signal on, so that other (and later) joiners get through as well
+        self.lock().term_notify.signal();
+        self.lock().exit_code.ok_or(KError::Killed)
+    }
+
+    pub fn get_priority(&self) -> TaskPriority {
+        self.lock().priority
+    }
+
+    // Deadline class can only be entered through set_deadline()
+    // An inherited priority stays in place till the mutex that caused it is released
+    pub fn set_priority(&self, priority: TaskPriority) {
+        assert!(priority != TaskPriority::Deadline, "set_priority() called with the Deadline class!");
+
+        self.update_priority(|task| {
+            let boosted = task.priority != task.base_priority && task.priority.is_higher_than(priority);
+            task.base_priority = priority;
+
+            if boosted {
+                task.priority
+            }
+            else {
+                priority
+            }
+        });
+    }
+
+    pub fn get_base_priority(&self) -> TaskPriority {
+        self.lock().base_priority
+    }
+
+    // Runs the task at priority, or at it's base priority if that's higher
+    // Used by priority inheritance. Deadline tasks are left alone, since they're already above everyone else
+    pub fn set_inherited_priority(&self, priority: TaskPriority) {
+        assert!(priority != TaskPriority::Deadline, "Deadline class can't be inherited!");
+
+        self.update_priority(|task| {
+            if priority.is_higher_than(task.base_priority) && task.base_priority != TaskPriority::Deadline {
+                priority
+            }
+            else {
+                task.base_priority
+            }
+        });
+    }
+
+    // Moves the task over to the class f returns
+    fn update_priority<F: FnOnce(&mut Task) -> TaskPriority>(&self, f: F) {
+        disable_preemption();
+        let core = {
+            let (mut sched_cb, core) = lock_task_scheduler(self);
+
+            let (old_priority, priority, status) = {
+                let mut task = self.lock();
+                let old_priority = task.priority;
+                let priority = f(&mut task);
+                task.priority = priority;
+
+                // Task leaves the Deadline class, so it gives up it's reservation
+                if priority != TaskPriority::Deadline && let Some(deadline) = task.deadline.take() {
+                    sched_cb.deadline_util -= deadline.params.get_utilization();
+                    task.affinity = deadline.saved_affinity;
+                }
+
+                (old_priority, priority, task.status)
+            };
+
+            // An active task sits in the run queue of it's old class, so move it over
+            // Status of an active task can't change while we hold the scheduler lock
+            // Leaving the Deadline class puts back it's old affinity, which could leave it misplaced
+            if status == TaskStatus::ACTIVE && old_priority != priority {
+                let task_node = sched_cb.remove_active(self, old_priority);
+                sched_cb.queue_active(task_node, &self.lock(), false);
+            }
+
+            core
+        };
+
+        // Let the scheduler re-evaluate in case this task now needs to preempt (or be preempted)
+        notify_other_cpu(core);
+        enable_preemption();
+    }
+
+    // Moves the task into the Deadline class, pinned to the core it's on right now
+    // Fails with KError::LimitExceeded if that core doesn't have enough bandwidth left
+    // set_priority() takes it back out of the class
+    pub fn set_deadline(&self, params: DeadlineParams) -> Result<(), KError> {
+        if !params.is_valid() {
+            return Err(KError::InvalidArgument);
+        }
+
+        disable_preemption();
+        let res = {
+            let (mut sched_cb, core) = lock_task_scheduler(self);
+            let mut task = self.lock();
+
+            let old_util = task.deadline.as_ref().map_or(0, |deadline| deadline.params.get_utilization());
+            let new_util = sched_cb.deadline_util - old_util + params.get_utilization();
+
+            if task.status == TaskStatus::TERMINATED {
+                Err(KError::InvalidArgument)
+            }
+            else if new_util > MAX_DEADLINE_UTIL {
+                Err(KError::LimitExceeded)
+            }
+            else {
+                sched_cb.deadline_util = new_util;
+
+                let saved_affinity = task.deadline.as_ref().map_or(task.affinity, |deadline| deadline.saved_affinity);
+                task.deadline = Some(DeadlineState::new(params, hal::get_time_ms(), saved_affinity));
+                task.affinity = CoreMask::from_core(core);
+
+                let old_priority = task.priority;
+                task.priority = TaskPriority::Deadline;
+                task.base_priority = TaskPriority::Deadline;
+                task.quanta = TaskPriority::Deadline.get_quanta();
+
+                if task.status == TaskStatus::ACTIVE && old_priority != TaskPriority::Deadline {
+                    let task_node = sched_cb.remove_active(self, old_priority);
+                    sched_cb.queue_active(task_node, &task, false);
+                }
+
+                Ok(core)
+            }
+        };
+
+        if let Ok(core) = res {
+            notify_other_cpu(core);
+        }
+
+        enable_preemption();
+
+        res.map(|_| ())
+    }
+
+    pub fn get_affinity(&self) -> CoreMask {
+        self.lock().affinity
+    }
+
+    pub fn get_stats(&self) -> SchedStats {
+        self.lock().get_stats()
+    }
+
+    // If the task isn't allowed on it's current core anymore, it's moved at the next reschedule of that core
+    // Deadline tasks stay on the core their bandwidth was reserved on
+    pub fn set_affinity(&self, affinity: CoreMask) -> Result<(), KError> {
+        if !affinity.is_valid() {
+            return Err(KError::InvalidArgument);
+        }
+
+        disable_preemption();
+        let res = {
+            let (mut sched_cb, core) = lock_task_scheduler(self);
+            let mut task = self.lock();
+
+            if task.deadline.is_some() {
+                Err(KError::InvalidArgument)
+            }
+            else {
+                task.affinity = affinity;
+
+                // A queued task is moved between the run queue and misplaced_tasks right here
+                // Running, waiting and suspended ones are sorted out once they're queued again
+                if task.status == TaskStatus::ACTIVE {
+                    let task_node = sched_cb.remove_active(self, task.priority);
+                    sched_cb.queue_active(task_node, &task, false);
+                }
+
+                Ok(core)
+            }
+        };
+
+        if let Ok(core) = res {
+            notify_other_cpu(core);
+        }
+
+        enable_preemption();
+
+        res.map(|_| ())
+    }
 }
\ No newline at end of file
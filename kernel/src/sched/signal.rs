@@ -0,0 +1,377 @@
+use crate::hal::{UserContext, is_user_range};
+use crate::mem::{copy_from_user, copy_to_user};
+use super::*;
+use core::mem::size_of;
+use kernel_intf::{KError, debug};
+
+// Signal numbers are 1 based and follow the usual POSIX numbering
+pub const MAX_SIGNALS: usize = 32;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGABRT: usize = 6;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+
+// These can't be caught, blocked or ignored
+const UNBLOCKABLE_SIGNALS: u64 = (1 << SIGKILL) | (1 << SIGSTOP);
+
+// Bits that can be set in a mask. Bit 0 and anything from MAX_SIGNALS up isn't a signal
+// Keeping bit 63 clear also keeps the old mask positive when it's handed back as a syscall return value
+const BLOCKABLE_SIGNALS: u64 = ((1 << MAX_SIGNALS) - 1) & !1 & !UNBLOCKABLE_SIGNALS;
+
+// Interrupted code could have live data below it's stack pointer
+const RED_ZONE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignalAction {
+    Default,
+    Ignore,
+    // Handler is entered as handler(signo, frame) and returns into restorer, which is expected to call sigreturn
+    Handler { entry: usize, restorer: usize }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignalMaskOp {
+    Block,
+    Unblock,
+    Set
+}
+
+#[derive(PartialEq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue
+}
+
+fn get_default_action(signo: usize) -> DefaultAction {
+    match signo {
+        SIGCHLD => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP => DefaultAction::Stop,
+        _ => DefaultAction::Terminate
+    }
+}
+
+fn is_valid_signal(signo: usize) -> bool {
+    signo > 0 && signo < MAX_SIGNALS
+}
+
+// Per process signal state. Whichever thread of the process heads back to user mode first takes the signal
+pub struct SignalState {
+    pending: u64,
+    blocked: u64,
+    // Set while the process is stopped by a signal
+    stopped: bool,
+    actions: [SignalAction; MAX_SIGNALS]
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            stopped: false,
+            actions: [SignalAction::Default; MAX_SIGNALS]
+        }
+    }
+
+    // State a forked child starts off with. Pending signals stay with the parent
+    pub fn inherit(&self) -> Self {
+        Self {
+            pending: 0,
+            blocked: self.blocked,
+            stopped: false,
+            actions: self.actions
+        }
+    }
+
+    // Only signals that somebody handles are kept around, the rest are dropped here
+    pub fn post_if_handled(&mut self, signo: usize) {
+        if matches!(self.actions[signo], SignalAction::Handler { .. }) {
+            self.pending |= 1 << signo;
+        }
+    }
+}
+
+// What gets pushed on the user stack when entering a handler
+#[repr(C)]
+struct SignalFrame {
+    signo: u64,
+    // Mask to go back to on sigreturn
+    blocked: u64,
+    context: UserContext
+}
+
+// Do not call this function from interrupt context
+pub fn send_signal(proc_id: usize, signo: usize) -> Result<(), KError> {
+    if !is_valid_signal(signo) || proc_id == 0 {
+        return Err(KError::InvalidArgument);
+    }
+
+    let proc = get_process_info(proc_id).ok_or(KError::InvalidArgument)?;
+
+    debug!("Sending signal {} to process {}", signo, proc_id);
+    let action = {
+        let mut guard = proc.lock();
+        let signals = guard.get_signal_state();
+
+        match signo {
+            SIGKILL => Some(DefaultAction::Terminate),
+            SIGSTOP => Some(DefaultAction::Stop),
+            _ => {
+                // SIGCONT continues the process even if it's caught or blocked
+                let continue_now = signo == SIGCONT;
+                let action = signals.actions[signo];
+
+                if action == SignalAction::Ignore {
+                    continue_now.then_some(DefaultAction::Continue)
+                }
+                // Default actions of blocked signals are taken once they are unblocked
+                else if signals.blocked & (1 << signo) != 0 || matches!(action, SignalAction::Handler { .. }) {
+                    signals.pending |= 1 << signo;
+                    continue_now.then_some(DefaultAction::Continue)
+                }
+                else {
+                    Some(get_default_action(signo))
+                }
+            }
+        }
+    };
+
+    if let Some(action) = action {
+        take_default_action(&proc, action)?;
+    }
+
+    Ok(())
+}
+
+fn take_default_action(proc: &KProcess, action: DefaultAction) -> Result<(), KError> {
+    let proc_id = proc.lock().get_id();
+
+    match action {
+        DefaultAction::Terminate => {
+            kill_process(proc_id);
+        },
+        DefaultAction::Stop => {
+            // Stop is not counted, unlike suspend
+            let was_stopped = core::mem::replace(&mut proc.lock().get_signal_state().stopped, true);
+            if !was_stopped {
+                suspend_process(proc_id)?;
+            }
+        },
+        DefaultAction::Continue => {
+            let was_stopped = core::mem::replace(&mut proc.lock().get_signal_state().stopped, false);
+            if was_stopped {
+                resume_process(proc_id)?;
+            }
+        },
+        DefaultAction::Ignore => {}
+    }
+
+    Ok(())
+}
+
+// Signal raised by a fault of the current thread that it can't get past, like a page fault nobody resolves
+// Called from the fault handler, so the signal is only marked pending. prepare_user_return takes it on the way out
+// Retrying the faulting instruction would just fault again, so it can't stay blocked or ignored
+pub fn force_signal(signo: usize) {
+    let Some(proc) = get_current_process() else {
+        return;
+    };
+
+    let mut guard = proc.lock();
+    let signals = guard.get_signal_state();
+
+    signals.blocked &= !(1 << signo);
+    if signals.actions[signo] == SignalAction::Ignore {
+        signals.actions[signo] = SignalAction::Default;
+    }
+
+    signals.pending |= 1 << signo;
+}
+
+// Returns the previous action
+pub fn set_signal_action(signo: usize, action: SignalAction) -> Result<SignalAction, KError> {
+    if !is_valid_signal(signo) || (1 << signo) & UNBLOCKABLE_SIGNALS != 0 {
+        return Err(KError::InvalidArgument);
+    }
+
+    if let SignalAction::Handler { entry, restorer } = action && (!is_user_range(entry, 0) || !is_user_range(restorer, 0)) {
+        return Err(KError::InvalidArgument);
+    }
+
+    let proc = get_current_process().ok_or(KError::InvalidArgument)?;
+    let mut guard = proc.lock();
+    let signals = guard.get_signal_state();
+
+    Ok(core::mem::replace(&mut signals.actions[signo], action))
+}
+
+// Returns the previous mask
+pub fn set_signal_mask(op: SignalMaskOp, mask: u64) -> Result<u64, KError> {
+    let proc = get_current_process().ok_or(KError::InvalidArgument)?;
+    let mut guard = proc.lock();
+    let signals = guard.get_signal_state();
+    let old_mask = signals.blocked;
+
+    signals.blocked = match op {
+        SignalMaskOp::Block => old_mask | mask,
+        SignalMaskOp::Unblock => old_mask & !mask,
+        SignalMaskOp::Set => mask
+    } & BLOCKABLE_SIGNALS;
+
+    Ok(old_mask)
+}
+
+// Called on the way back to user mode, with the state user code is about to resume with. That's the syscall exit,
+// the outermost interrupt exit and the start of a forked thread
+// Runs in the context of the thread that's returning, with interrupts enabled and no locks held. It's free to block,
+// since default actions are taken right here. Returns true if the context was changed
+pub fn prepare_user_return(context: &mut UserContext) -> bool {
+    let task = match get_current_task() {
+        Some(task) => task,
+        None => return false
+    };
+
+    let (process, restored) = {
+        let mut guard = task.lock();
+        (guard.get_process(), guard.take_resume_context())
+    };
+
+    // This thread just came back from a handler (or is the first one of a forked process)
+    let changed = if let Some(restored) = restored {
+        *context = restored;
+        true
+    }
+    else {
+        false
+    };
+
+    let process = match process {
+        Some(process) => process,
+        None => return changed
+    };
+
+    loop {
+        let (proc_id, signo, action, blocked) = {
+            let mut guard = process.lock();
+            let proc_id = guard.get_id();
+            let signals = guard.get_signal_state();
+
+            let deliverable = signals.pending & !signals.blocked;
+            if deliverable == 0 {
+                return changed;
+            }
+
+            let signo = deliverable.trailing_zeros() as usize;
+            signals.pending &= !(1 << signo);
+
+            let blocked = signals.blocked;
+            let action = signals.actions[signo];
+
+            // Signal stays blocked while it's handler runs
+            if let SignalAction::Handler { .. } = action {
+                signals.blocked |= 1 << signo;
+            }
+
+            (proc_id, signo, action, blocked)
+        };
+
+        match action {
+            SignalAction::Handler { entry, restorer } => {
+                if push_signal_frame(context, signo, blocked, entry, restorer) {
+                    return true;
+                }
+
+                // No usable stack to run the handler on
+                debug!("Bad user stack while delivering signal {} to process {}", signo, proc_id);
+                let _ = take_default_action(&process, DefaultAction::Terminate);
+                return changed;
+            },
+            SignalAction::Ignore => {},
+            SignalAction::Default => {
+                // Stop comes back here once the process is continued
+                let _ = take_default_action(&process, get_default_action(signo));
+            }
+        }
+    }
+}
+
+fn push_signal_frame(context: &mut UserContext, signo: usize, blocked: u64, entry: usize, restorer: usize) -> bool {
+    let frame_size = size_of::<SignalFrame>();
+
+    // Handler is entered with the restorer as it's return address, as if it was called from there
+    let frame_base = match context.get_stack_pointer().checked_sub(RED_ZONE + frame_size) {
+        Some(base) => base & !0xf,
+        None => return false
+    };
+
+    let stack = frame_base - size_of::<usize>();
+    if !is_user_range(stack, frame_size + size_of::<usize>()) {
+        return false;
+    }
+
+    let frame = SignalFrame {
+        signo: signo as u64,
+        blocked,
+        context: *context
+    };
+
+    // Stack could still be shared copy-on-write with a forked child
+    let res = unsafe {
+        copy_to_user(frame_base as *mut u8, &frame as *const SignalFrame as *const u8, frame_size)
+        .and_then(|_| copy_to_user(stack as *mut u8, &restorer as *const usize as *const u8, size_of::<usize>()))
+    };
+
+    if res.is_err() {
+        return false;
+    }
+
+    context.set_entry(entry, signo, frame_base, stack);
+    true
+}
+
+// frame points to the signal frame, which is where the stack pointer is once the handler returns
+// Context is switched back on the way out of the syscall
+pub fn signal_return(frame: usize) -> Result<(), KError> {
+    if !is_user_range(frame, size_of::<SignalFrame>()) {
+        return Err(KError::InvalidArgument);
+    }
+
+    let mut signal_frame = SignalFrame {
+        signo: 0,
+        blocked: 0,
+        context: UserContext::default()
+    };
+
+    // Frame pointer comes straight from user code, so it may not even be mapped
+    unsafe {
+        copy_from_user(&mut signal_frame as *mut SignalFrame as *mut u8, frame as *const u8, size_of::<SignalFrame>())?;
+    }
+
+    if !signal_frame.context.sanitize() {
+        return Err(KError::InvalidArgument);
+    }
+
+    let task = get_current_task().ok_or(KError::InvalidArgument)?;
+    let process = task.lock().get_process().ok_or(KError::InvalidArgument)?;
+
+    process.lock().get_signal_state().blocked = signal_frame.blocked & BLOCKABLE_SIGNALS;
+    task.lock().set_resume_context(signal_frame.context);
+
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use crate::sync::{KSem, Spinlock};
+use crate::sched;
 use crate::mem::PoolAllocatorGlobal;
 use alloc::sync::Arc;
 use kernel_intf::KError;
@@ -8,21 +9,51 @@ pub type KTimerInnerType = Arc<Spinlock<KTimerInner>, PoolAllocatorGlobal>;
 // We'll introduce periodic timers later
 pub struct KTimerInner {
     init_count: usize,
+    // Absolute expiry time (in ms since boot). Only valid once the timer is armed
+    deadline: usize,
+    // Core whose timer list the timer is on. None while it isn't queued anywhere
+    core: Option<usize>,
     wait_sem: KSem    
 }
 
 impl KTimerInner {
-    fn new(init_count: usize) -> Self {
+    fn new(init_count: usize, wait_sem: KSem) -> Self {
         Self {
             init_count,
-            wait_sem: KSem::new(0, 1)
+            deadline: 0,
+            core: None,
+            wait_sem
         }
     }
 
-    pub fn update_timer_count(&mut self, count: usize) -> bool {
-        self.init_count = self.init_count.saturating_sub(count);
-        
-        self.init_count == 0
+    // Timer starts counting down from the time it's added to the scheduler
+    pub fn arm(&mut self, now: usize, core: usize) {
+        self.deadline = now + self.init_count;
+        self.core = Some(core);
+    }
+
+    // Timer was taken off it's timer list without expiring
+    pub fn disarm(&mut self) {
+        self.core = None;
+    }
+
+    pub fn get_deadline(&self) -> usize {
+        self.deadline
+    }
+
+    pub fn get_core(&self) -> Option<usize> {
+        self.core
+    }
+
+    // Once expired, the timer won't wait anymore
+    pub fn check_expiry(&mut self, now: usize) -> bool {
+        if now < self.deadline {
+            return false;
+        }
+
+        self.init_count = 0;
+        self.core = None;
+        true
     }
 
     pub fn get_semaphore(&self) -> KSem {
@@ -38,11 +69,39 @@ impl KTimer {
     pub fn new(init_count: usize) -> Self {
         Self {
             inner: Arc::new_in(Spinlock::new(
-                KTimerInner::new(init_count)
+                KTimerInner::new(init_count, KSem::new(0, 1))
+            ), PoolAllocatorGlobal)
+        }
+    }
+
+    // Expiry signals the given semaphore instead of the timer's own
+    // Useful when one waiter needs to hear about several timers
+    pub fn new_with_semaphore(init_count: usize, sem: KSem) -> Self {
+        Self {
+            inner: Arc::new_in(Spinlock::new(
+                KTimerInner::new(init_count, sem)
             ), PoolAllocatorGlobal)
         }
     }
 
+    // Arms the timer without waiting on it
+    pub fn start(&self) -> Result<(), KError> {
+        sched::start_timer(Arc::clone(&self.inner))
+    }
+
+    // Takes the timer back before it expires. Returns false if it had already expired (or was never started)
+    pub fn cancel(&self) -> bool {
+        sched::cancel_timer(&self.inner)
+    }
+
+    pub fn get_inner(&self) -> KTimerInnerType {
+        Arc::clone(&self.inner)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.inner.lock().init_count == 0
+    }
+
     pub fn wait(&self) -> Result<(), KError> {
         let inner_clones  = {
             let inner = self.inner.lock();
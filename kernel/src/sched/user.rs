@@ -2,8 +2,8 @@ use core::alloc::Layout;
 use common::PAGE_SIZE;
 use kernel_intf::{KError, info};
 use crate::cpu::Stack;
-use crate::hal::{MAX_ARCH_ARGS, copy_user_memory, transfer_control_to_user};
-use crate::mem::{PageDescriptor, allocate_memory};
+use crate::hal::{MAX_ARCH_ARGS, UserContext, copy_user_memory, transfer_control_to_user, transfer_control_to_user_context};
+use crate::mem::{PageDescriptor, allocate_memory, deallocate_memory};
 use super::*;
 use kernel_intf::*;
 
@@ -21,21 +21,26 @@ unsafe extern "C" {
 }
 
 
-const MAX_SYSCALLS: usize = 6;
+const MAX_SYSCALLS: usize = 11;
 
-static SYSCALL_TABLE: [fn(&[u64; MAX_ARCH_ARGS]) -> i64; MAX_SYSCALLS] = [
+static SYSCALL_TABLE: [fn(&[u64; MAX_ARCH_ARGS], &UserContext) -> i64; MAX_SYSCALLS] = [
     sys_exit_handler,
     sys_thread_exit_handler,
     sys_write_handler,
     sys_delay_handler,
     sys_thread_handler,
-    sys_process_handler
+    sys_process_handler,
+    sys_sigaction_handler,
+    sys_sigprocmask_handler,
+    sys_kill_handler,
+    sys_sigreturn_handler,
+    sys_fork_handler
 ];
 
 
 // Must be called from valid process context 
-pub fn create_user_thread(handler: fn() -> !) -> Result<KThread, KError> {
-    let res = create_thread_do_work(user_init_handler,  Some(handler));
+pub fn create_user_thread(handler: fn() -> !, priority: Option<TaskPriority>) -> Result<KThread, KError> {
+    let res = create_thread_do_work(user_init_handler,  Some(handler), priority.unwrap_or_default(), None, None, None);
 
     if res.is_err() {
         info!("User thread creation failed!");
@@ -49,9 +54,15 @@ pub fn user_init_handler() -> ! {
     // Allocate the user memory range for init handler
     // Transfer control to user
 
-    let mut stack = Stack::new_user_stack().expect("Failed to create user stack!");
+    let mut stack = match Stack::new_user_stack() {
+        Ok(stack) => stack,
+        Err(e) => abort_user_init(e)
+    };
+
     info!("Created new user stack with base:{:#X}", stack.get_stack_base()); 
-    add_memory_range_to_cur_process(stack.get_alloc_base(), stack.get_stack_size(), true);
+    if let Err(e) = add_memory_range_to_cur_process(stack.get_alloc_base(), stack.get_stack_size(), true) {
+        abort_user_init(e);
+    }
     
     // User stacks will be cleaned up by the process manager, so remove ownership
     let stack_base = Stack::into_inner(&mut stack).addr().get();
@@ -66,15 +77,22 @@ pub fn user_init_handler() -> ! {
 
     let user_stub_size = user_fn_last - user_fn_top;
 
-    let user_stub_base = allocate_memory(Layout::from_size_align(user_stub_size, PAGE_SIZE).unwrap(), PageDescriptor::VIRTUAL 
-    | PageDescriptor::USER).expect("User stub allocation failed!");
+    let user_stub_layout = Layout::from_size_align(user_stub_size, PAGE_SIZE).unwrap();
+    let user_stub_base = match allocate_memory(user_stub_layout, PageDescriptor::VIRTUAL | PageDescriptor::USER) {
+        Ok(base) => base,
+        Err(e) => abort_user_init(e)
+    };
 
     info!("Allocated user stub at addr: {:#X} with size {}", user_stub_base.addr(), user_stub_size);
     unsafe {
         copy_user_memory(user_stub_base, &USER_FN_START as *const u8, user_stub_size);
     }
 
-    add_memory_range_to_cur_process(user_stub_base.addr(), user_stub_size, true);
+    if let Err(e) = add_memory_range_to_cur_process(user_stub_base.addr(), user_stub_size, true) {
+        deallocate_memory(user_stub_base, user_stub_layout, PageDescriptor::VIRTUAL | PageDescriptor::USER)
+        .expect("Failed to deallocate user stub!");
+        abort_user_init(e);
+    }
 
     // Let parent process know that user init is complete
     // Ensure that this process is dropped beyond this block since we won't return to this function
@@ -91,32 +109,51 @@ pub fn user_init_handler() -> ! {
 }
 
 
-pub fn syscall_dispatcher(syscall_number: u64, syscall_args: &[u64; MAX_ARCH_ARGS]) -> i64 {
-    if syscall_number as usize >= MAX_SYSCALLS {
-        return E_INVALID;
-    } 
+// User thread couldn't be setup (Usually because the process hit one of it's limits)
+fn abort_user_init(e: KError) -> ! {
+    info!("User thread setup failed with error: {}", e);
 
-    SYSCALL_TABLE[syscall_number as usize](syscall_args)
-}
+    // Parent could be waiting on init to complete
+    if let Some(process) = get_current_process() {
+        process.lock().complete_init();
+    }
 
-fn sys_exit_handler(_args: &[u64; MAX_ARCH_ARGS]) -> i64 {
-    let id = get_current_process_id().expect("Called sys_exit_handler from idle task!");
+    exit_thread();
+}
 
-    kill_process(id);
+// First thing a forked thread runs. Goes straight back to user mode, with the context it was created with
+pub fn fork_return_handler() -> ! {
+    // Pending signals get delivered on the way out as well
+    let mut context = UserContext::default();
+    if !prepare_user_return(&mut context) {
+        panic!("Forked thread started without it's user context!");
+    }
 
-    E_SUCCESS
+    transfer_control_to_user_context(&context);
 }
 
-fn sys_thread_exit_handler(_args: &[u64; MAX_ARCH_ARGS]) -> i64 {
-    let id = get_current_task_id().expect("Called sys_thread_exit_handler from idle task!");
 
-    kill_thread(id);
+// context is the state user code resumes with once the syscall returns
+pub fn syscall_dispatcher(syscall_number: u64, syscall_args: &[u64; MAX_ARCH_ARGS], context: &UserContext) -> i64 {
+    if syscall_number as usize >= MAX_SYSCALLS {
+        return E_INVALID;
+    } 
 
-    E_SUCCESS
+    SYSCALL_TABLE[syscall_number as usize](syscall_args, context)
+}
+
+// Arg 1 = exit code
+fn sys_exit_handler(args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
+    exit_process_with(args[0] as i64);
+}
+
+// Arg 1 = exit code
+fn sys_thread_exit_handler(args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
+    exit_thread_with(args[0] as i64);
 }
 
 // Arg1 = pointer to string, arg2 = length of string
-fn sys_write_handler(args: &[u64; MAX_ARCH_ARGS]) -> i64 {
+fn sys_write_handler(_args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
     //let mut str_buf = vec![0u8; args[1] as usize];
     //let str_buf_ptr = str_buf.as_mut_ptr();
 
@@ -142,22 +179,74 @@ fn sys_write_handler(args: &[u64; MAX_ARCH_ARGS]) -> i64 {
 }
 
 // Arg 1 = delay in ms
-fn sys_delay_handler(args: &[u64; MAX_ARCH_ARGS]) -> i64 {
+fn sys_delay_handler(args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
     debug!("Delaying thread");
     delay_ms(args[0] as usize);
 
     E_SUCCESS
 }
 
-fn sys_thread_handler(_args: &[u64; MAX_ARCH_ARGS]) -> i64 {
+fn sys_thread_handler(_args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
     info!("Creating new user thread..");
-    let stat: KError = create_user_thread(|| {loop{}}).into();
+    let stat: KError = create_user_thread(|| {loop{}}, None).into();
 
     stat.into()
 }
 
-fn sys_process_handler(_args: &[u64; MAX_ARCH_ARGS]) -> i64 {
-    let stat: KError = create_process(|| {loop{}}, true).into();
+fn sys_process_handler(_args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
+    let stat: KError = create_process(|| {loop{}}, true, None).into();
 
     stat.into()
 }
+
+// Arg 1 = signal number, arg 2 = handler (0 = default, 1 = ignore), arg 3 = restorer
+fn sys_sigaction_handler(args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
+    let action = match args[1] {
+        0 => SignalAction::Default,
+        1 => SignalAction::Ignore,
+        entry => SignalAction::Handler { entry: entry as usize, restorer: args[2] as usize }
+    };
+
+    let stat: KError = set_signal_action(args[0] as usize, action).into();
+
+    stat.into()
+}
+
+// Arg 1 = operation (0 = block, 1 = unblock, 2 = set), arg 2 = mask
+// Returns the previous mask
+fn sys_sigprocmask_handler(args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
+    let op = match args[0] {
+        0 => SignalMaskOp::Block,
+        1 => SignalMaskOp::Unblock,
+        2 => SignalMaskOp::Set,
+        _ => return E_INVALID
+    };
+
+    match set_signal_mask(op, args[1]) {
+        Ok(old_mask) => old_mask as i64,
+        Err(e) => e.into()
+    }
+}
+
+// Arg 1 = process id, arg 2 = signal number
+fn sys_kill_handler(args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
+    let stat: KError = send_signal(args[0] as usize, args[1] as usize).into();
+
+    stat.into()
+}
+
+// Arg 1 = pointer to the signal frame (Stack pointer once the handler returns)
+// On success, the syscall returns into the interrupted context instead
+fn sys_sigreturn_handler(args: &[u64; MAX_ARCH_ARGS], _context: &UserContext) -> i64 {
+    let stat: KError = signal_return(args[0] as usize).into();
+
+    stat.into()
+}
+
+// Returns the child process id in the parent and 0 in the child
+fn sys_fork_handler(_args: &[u64; MAX_ARCH_ARGS], context: &UserContext) -> i64 {
+    match fork(context) {
+        Ok(child_id) => child_id as i64,
+        Err(e) => e.into()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,198 @@
+use crate::cpu::{MAX_CPUS, PerCpu, get_total_cores};
+use crate::ds::*;
+use crate::sync::{KSem, Spinlock};
+use super::{CoreMask, KTimer, TaskPriority, create_thread_with_affinity};
+use crate::hal;
+use alloc::format;
+use core::mem::replace;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use kernel_intf::{KError, debug};
+
+// Work item runs later on the per-core worker thread, so it's free to block
+// It's owned by whoever queues it (Usually a static), so queueing it never allocates. That's what makes queue_work safe
+// to call from an ISR. An item that is already queued can't be queued again until it starts running
+pub struct Work {
+    func: fn(usize),
+    arg: usize,
+    queued: AtomicBool,
+    // Link to the next item of the list it's queued on. Guarded by that list's lock
+    next: AtomicPtr<Work>
+}
+
+impl Work {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        Self {
+            func,
+            arg,
+            queued: AtomicBool::new(false),
+            next: AtomicPtr::new(null_mut())
+        }
+    }
+
+    pub fn run(&self) {
+        // Cleared first, so that the item is free to queue itself again
+        self.queued.store(false, Ordering::Release);
+        (self.func)(self.arg);
+    }
+}
+
+// First in first out list of work items, linked through the items themselves
+pub struct WorkList {
+    head: Option<&'static Work>,
+    tail: Option<&'static Work>
+}
+
+impl WorkList {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None
+        }
+    }
+
+    // Returns false if the item is already queued
+    pub fn push(&mut self, work: &'static Work) -> bool {
+        if work.queued.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+
+        work.next.store(null_mut(), Ordering::Relaxed);
+        match self.tail {
+            Some(tail) => tail.next.store(work as *const Work as *mut Work, Ordering::Relaxed),
+            None => self.head = Some(work)
+        }
+
+        self.tail = Some(work);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<&'static Work> {
+        let work = self.head?;
+        self.head = unsafe { work.next.load(Ordering::Relaxed).as_ref() };
+        if self.head.is_none() {
+            self.tail = None;
+        }
+
+        Some(work)
+    }
+}
+
+// Delayed work carries it's own copy of the item, since it needs a timer of it's own anyway
+#[derive(Clone, Copy)]
+struct WorkItem {
+    func: fn(usize),
+    arg: usize
+}
+
+struct DelayedWork {
+    item: WorkItem,
+    timer: KTimer
+}
+
+struct WorkQueue {
+    pending: WorkList,
+    delayed: DynList<DelayedWork>,
+    // Worker sleeps on this. Setup in init()
+    event: Option<KSem>
+}
+
+impl WorkQueue {
+    const fn new() -> Self {
+        Self {
+            pending: WorkList::new(),
+            delayed: List::new(),
+            event: None
+        }
+    }
+}
+
+static WORK_QUEUES: PerCpu<Spinlock<WorkQueue>> = PerCpu::new_with(
+    [const {Spinlock::new(WorkQueue::new())}; MAX_CPUS]
+);
+
+// Queue work on the current core's worker thread
+// Safe to call from interrupt context, which makes it the way to push slow stuff out of an ISR
+// Returns false if the item was already queued, in which case it's going to run once for both
+pub fn queue_work(work: &'static Work) -> bool {
+    let (queued, event) = {
+        let mut queue = WORK_QUEUES.local().lock();
+        (queue.pending.push(work), queue.event.clone())
+    };
+
+    // Worker isn't up yet. It'll pick this up when it starts
+    if queued && let Some(event) = event {
+        event.signal();
+    }
+
+    queued
+}
+
+// Same as queue_work, but the item only runs once delay_ms has passed
+// This one allocates the timer and the list node, so it can't be called from interrupt context
+pub fn queue_delayed_work(func: fn(usize), arg: usize, delay_ms: usize) -> Result<(), KError> {
+    assert!(hal::are_interrupts_enabled(), "queue_delayed_work() called with interrupts disabled!");
+
+    let mut queue = WORK_QUEUES.local().lock();
+    let event = queue.event.clone().ok_or(KError::InvalidArgument)?;
+
+    // Timer reports expiry to the worker directly
+    let timer = KTimer::new_with_semaphore(delay_ms, event);
+    queue.delayed.add_node(DelayedWork { item: WorkItem { func, arg }, timer })?;
+
+    // Item is already in the list, so the worker can't miss it even if the timer fires right away
+    let res = queue.delayed.last().unwrap().timer.start();
+    if res.is_err() {
+        queue.delayed.pop_node();
+    }
+
+    res
+}
+
+fn worker_thread() -> ! {
+    let event = WORK_QUEUES.local().lock().event.clone().expect("Worker started without it's event!");
+
+    loop {
+        // Worker has no reason to get killed, so just go around again
+        let _ = event.wait();
+
+        let (mut work, expired) = {
+            let mut queue = WORK_QUEUES.local().lock();
+            let work = replace(&mut queue.pending, WorkList::new());
+            let mut expired: DynList<WorkItem> = List::new();
+
+            while let Some(delayed) = queue.delayed.find_and_remove(|delayed| delayed.timer.is_expired()) {
+                expired.add_node(delayed.item).expect("Failed to move delayed work!");
+            }
+
+            (work, expired)
+        };
+
+        // Queue lock is released, so items are free to queue more work
+        while let Some(item) = work.pop() {
+            item.run();
+        }
+
+        for item in expired.iter() {
+            (item.func)(item.arg);
+        }
+    }
+}
+
+pub fn init() {
+    for core in 0..get_total_cores() {
+        let event = KSem::new(0, 1);
+        unsafe {
+            WORK_QUEUES.get(core).lock().event = Some(event.clone());
+        }
+
+        create_thread_with_affinity(worker_thread, Some(TaskPriority::RealTime), CoreMask::from_core(core))
+        .expect("Failed to create worker thread!")
+        .set_name(&format!("worker/{}", core));
+
+        // Anything queued before the worker existed
+        event.signal();
+
+        debug!("Started worker thread on core {}", core);
+    }
+}
@@ -0,0 +1,140 @@
+use core::mem::take;
+use core::ops::DerefMut;
+use super::{KMutex, KMutexGuard, KSem, Spinlock, SpinlockGuard};
+use crate::ds::*;
+use crate::sched::{self, KTimer};
+use kernel_intf::KError;
+
+// Lock guards a KCondvar can sleep with
+// The lock is let go while the task sleeps and is taken again before the wait returns
+pub trait CondvarGuard: Sized {
+    // Whatever is needed to take the lock again
+    type Lock: Copy;
+
+    fn get_lock(&self) -> Self::Lock;
+    fn relock(lock: Self::Lock) -> Result<Self, KError>;
+}
+
+impl<'a, T> CondvarGuard for SpinlockGuard<'a, T> {
+    type Lock = &'a Spinlock<T>;
+
+    fn get_lock(&self) -> Self::Lock {
+        self.get_spinlock()
+    }
+
+    fn relock(lock: Self::Lock) -> Result<Self, KError> {
+        Ok(lock.lock())
+    }
+}
+
+impl<'a, T> CondvarGuard for KMutexGuard<'a, T> {
+    type Lock = &'a KMutex<T>;
+
+    fn get_lock(&self) -> Self::Lock {
+        self.get_mutex()
+    }
+
+    fn relock(lock: Self::Lock) -> Result<Self, KError> {
+        lock.lock()
+    }
+}
+
+struct Waiter {
+    task_id: usize,
+    // Every waiter sleeps on it's own semaphore, so that a timeout only ever wakes up the one it belongs to
+    wake: KSem
+}
+
+// Condition variable. Waiters are woken up in the order they started waiting
+// Wake ups can be spurious, so the condition has to be checked again after a wait (wait_while does that)
+// notify_one() and notify_all() can be called from interrupt context. Waiting can't
+pub struct KCondvar {
+    waiters: Spinlock<DynList<Waiter>>
+}
+
+impl KCondvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Spinlock::new(List::new())
+        }
+    }
+
+    // On error, the lock isn't held anymore
+    pub fn wait<G: CondvarGuard>(&self, guard: G) -> Result<G, KError> {
+        self.wait_do_work(guard, None).map(|(guard, _)| guard)
+    }
+
+    // Second value is true if nobody notified us within timeout_ms
+    pub fn wait_timeout<G: CondvarGuard>(&self, guard: G, timeout_ms: usize) -> Result<(G, bool), KError> {
+        self.wait_do_work(guard, Some(timeout_ms))
+    }
+
+    // Waits for as long as condition holds. Returns with the lock held and the condition false
+    pub fn wait_while<G, F>(&self, mut guard: G, mut condition: F) -> Result<G, KError>
+    where
+        G: CondvarGuard + DerefMut,
+        F: FnMut(&mut G::Target) -> bool
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+
+        Ok(guard)
+    }
+
+    fn wait_do_work<G: CondvarGuard>(&self, guard: G, timeout_ms: Option<usize>) -> Result<(G, bool), KError> {
+        let task_id = sched::get_current_task_id()
+        .expect("KCondvar::wait() called from idle task!!");
+        let wake = KSem::new(0, 1);
+
+        // We're queued up before the lock is let go. A notify that comes in between then and the wait below
+        // leaves the semaphore signalled, so the wait falls right through instead of missing it
+        self.waiters.lock().add_node(Waiter { task_id, wake: wake.clone() })?;
+
+        let lock = guard.get_lock();
+        drop(guard);
+
+        // Timer reports expiry on our semaphore
+        let timer = timeout_ms.map(|timeout_ms| KTimer::new_with_semaphore(timeout_ms, wake.clone()));
+        let res = match &timer {
+            Some(timer) => timer.wait(),
+            None => wake.wait()
+        };
+
+        // Notified (or killed) before the timeout, so the timer would only keep it's core ticking for nothing
+        if let Some(timer) = &timer {
+            timer.cancel();
+        }
+
+        // Still queued means nobody notified us
+        let timed_out = self.waiters.lock().find_and_remove(|waiter| waiter.task_id == task_id).is_some();
+
+        if let Err(e) = res {
+            // Pass on a notify we got but won't act on
+            if !timed_out {
+                self.notify_one();
+            }
+
+            return Err(e);
+        }
+
+        Ok((G::relock(lock)?, timed_out))
+    }
+
+    pub fn notify_one(&self) {
+        let waiter = self.waiters.lock().find_and_remove(|_| true);
+
+        // Signalled with the list unlocked, so that the waiter doesn't wake up only to spin on it
+        if let Some(waiter) = waiter {
+            waiter.wake.signal();
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let waiters = take(&mut *self.waiters.lock());
+
+        for waiter in waiters.iter() {
+            waiter.wake.signal();
+        }
+    }
+}
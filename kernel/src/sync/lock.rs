@@ -8,6 +8,11 @@ use kernel_intf::Lock;
 use crate::hal;
 use common::ptr_to_ref_mut;
 
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+use core::sync::atomic::AtomicU16;
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+use super::lockdep;
+
 // This assumption is used by Lock variable in kernel_intf
 const _: () = {
     assert!(core::mem::size_of::<hal::Spinlock>() == 8);
@@ -20,6 +25,8 @@ pub struct SpinlockGuard<'a, T> {
     _lock: MutexGuard<'a, u32>,
 #[cfg(not(test))]
     int_status: bool,
+    // Lets the guard be given up and taken again later (See KCondvar)
+    owner: &'a Spinlock<T>,
     data: *mut T
 }
 
@@ -37,9 +44,18 @@ impl<T> DerefMut for SpinlockGuard<'_, T> {
     }
 }
 
+impl<'a, T> SpinlockGuard<'a, T> {
+    pub fn get_spinlock(&self) -> &'a Spinlock<T> {
+        self.owner
+    }
+}
+
 #[cfg(not(test))]
 impl<T> Drop for SpinlockGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(all(debug_assertions, feature = "deadlock_detection"))]
+        lockdep::lock_released(&self.owner.class);
+
         self.lock.unlock();
         hal::enable_interrupts(self.int_status);
     }
@@ -50,6 +66,11 @@ pub struct Spinlock<T> {
     pub lock: hal::Spinlock,
 #[cfg(test)]
     pub lock: Mutex<u32>,
+    // Lock validator class. Looked up on first use
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    class: AtomicU16,
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    class_name: Option<&'static str>,
     data: UnsafeCell<T>
 }
 
@@ -57,14 +78,34 @@ unsafe impl<T: Send> Sync for Spinlock<T>{}
 
 impl<T> Spinlock<T> {
     pub const fn new(data: T) -> Self {
+        Self::create(data, None)
+    }
+
+    // Gives the lock a class of it's own in the lock validator, instead of sharing one with every Spinlock guarding
+    // the same type. Meant for locks guarding generic stuff like ()
+    pub const fn new_with_class(data: T, class: &'static str) -> Self {
+        Self::create(data, Some(class))
+    }
+
+    #[allow(unused_variables)]
+    const fn create(data: T, class: Option<&'static str>) -> Self {
         Spinlock {
 #[cfg(not(test))]
             lock: hal::Spinlock::new(),
 #[cfg(test)]
             lock: Mutex::new(0),
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+            class: AtomicU16::new(lockdep::NO_CLASS),
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+            class_name: class,
             data: UnsafeCell::new(data)
         }
     }
+
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    fn get_class_name(&self) -> &'static str {
+        self.class_name.unwrap_or(core::any::type_name::<T>())
+    }
     
 #[cfg(not(test))]
     pub fn lock(&self) -> SpinlockGuard<'_, T> {
@@ -72,6 +113,8 @@ impl<T> Spinlock<T> {
 
         #[cfg(all(debug_assertions, feature = "deadlock_detection"))]
         {
+            lockdep::lock_acquiring(&self.class, self.get_class_name());
+
             let mut count = 0;        
             while !self.lock.try_lock() {
                 count += 1;
@@ -89,13 +132,39 @@ impl<T> Spinlock<T> {
         #[cfg(not(all(debug_assertions, feature = "deadlock_detection")))]
         self.lock.lock();
 
-        SpinlockGuard { lock: &self.lock, int_status, data: self.data.get()}
+        #[cfg(all(debug_assertions, feature = "deadlock_detection"))]
+        lockdep::lock_acquired(&self.class, self.get_class_name());
+
+        SpinlockGuard { lock: &self.lock, int_status, owner: self, data: self.data.get()}
     }
 
 #[cfg(test)]
     pub fn lock(&self) -> SpinlockGuard<'_, T> {
         let guard = self.lock.lock().unwrap();
-        SpinlockGuard { _lock: guard, data: self.data.get()}
+        SpinlockGuard { _lock: guard, owner: self, data: self.data.get()}
+    }
+
+    // Single attempt at acquiring the lock. Use this when another lock of the same kind is already held
+#[cfg(not(test))]
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let int_status = hal::disable_interrupts();
+
+        if !self.lock.try_lock() {
+            hal::enable_interrupts(int_status);
+            return None;
+        }
+
+        // Can't deadlock, so there's no order to check
+        #[cfg(all(debug_assertions, feature = "deadlock_detection"))]
+        lockdep::lock_acquired(&self.class, self.get_class_name());
+
+        Some(SpinlockGuard { lock: &self.lock, int_status, owner: self, data: self.data.get()})
+    }
+
+#[cfg(test)]
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let guard = self.lock.try_lock().ok()?;
+        Some(SpinlockGuard { _lock: guard, owner: self, data: self.data.get()})
     }
 
     // This gives access to underlying data without locking
@@ -0,0 +1,326 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use crate::cpu::{self, MAX_CPUS, PerCpu};
+use crate::{hal, infra};
+use kernel_intf::println;
+
+// Lock order validator for the deadlock_detection feature
+// Every Spinlock and RwSpinlock belongs to a class, which is named after the type it guards unless it was given a name
+// Each core keeps track of the classes it holds, and taking a lock while holding others records a "held -> taken" order
+// An order that closes a cycle with the ones seen before could deadlock (Even if it didn't this time), so it gets reported
+// along with where the other order was first seen. Only the first inversion is reported, validation stops after that
+// Locks of the same class aren't checked against each other, since those are taken in some order of their own
+// (Task id, address etc). The spin timeout in Spinlock::lock still catches those
+
+const MAX_LOCK_CLASSES: usize = 128;
+const MAX_LOCK_ORDERS: usize = 256;
+const MAX_HELD_LOCKS: usize = 32;
+const LOCKDEP_STACK_DEPTH: usize = 16;
+const CLASS_WORDS: usize = MAX_LOCK_CLASSES / 64;
+
+// Class of a lock that hasn't been taken yet. Class ids start at 1
+pub const NO_CLASS: u16 = 0;
+
+#[derive(Clone, Copy)]
+struct LockOrder {
+    from: u16,
+    to: u16,
+    // Where the order was first seen. Empty for orders declared up front
+    stack: [usize; LOCKDEP_STACK_DEPTH],
+    depth: usize
+}
+
+struct LockGraph {
+    // Indexed by class id
+    classes: [&'static str; MAX_LOCK_CLASSES],
+    num_classes: usize,
+    // Bit `to` is set in deps[from] once class `to` was taken with class `from` held
+    deps: [[u64; CLASS_WORDS]; MAX_LOCK_CLASSES],
+    orders: [LockOrder; MAX_LOCK_ORDERS],
+    num_orders: usize
+}
+
+// Guards the graph. It's a bare hal::Spinlock, since taking a Spinlock here would come right back to the validator
+struct LockGraphCell {
+    lock: hal::Spinlock,
+    graph: UnsafeCell<LockGraph>
+}
+
+unsafe impl Sync for LockGraphCell {}
+
+struct HeldLocks {
+    classes: [u16; MAX_HELD_LOCKS],
+    depth: usize,
+    // Set while the validator runs on this core, so that locks it takes on the way are left alone
+    busy: bool
+}
+
+// Only touched by it's own core with interrupts disabled
+struct HeldLocksCell(UnsafeCell<HeldLocks>);
+
+unsafe impl Sync for HeldLocksCell {}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static GRAPH: LockGraphCell = LockGraphCell {
+    lock: hal::Spinlock::new(),
+    graph: UnsafeCell::new(LockGraph {
+        classes: [""; MAX_LOCK_CLASSES],
+        // Skip NO_CLASS
+        num_classes: 1,
+        deps: [[0; CLASS_WORDS]; MAX_LOCK_CLASSES],
+        orders: [LockOrder { from: NO_CLASS, to: NO_CLASS, stack: [0; LOCKDEP_STACK_DEPTH], depth: 0 }; MAX_LOCK_ORDERS],
+        num_orders: 0
+    })
+};
+
+static HELD_LOCKS: PerCpu<HeldLocksCell> = PerCpu::new_with(
+    [const {HeldLocksCell(UnsafeCell::new(HeldLocks { classes: [NO_CLASS; MAX_HELD_LOCKS], depth: 0, busy: false }))}; MAX_CPUS]
+);
+
+impl LockGraph {
+    fn get_class(&mut self, name: &'static str) -> Option<u16> {
+        if let Some(class) = self.classes[1..self.num_classes].iter().position(|&class| class == name) {
+            return Some(class as u16 + 1);
+        }
+
+        if self.num_classes == MAX_LOCK_CLASSES {
+            return None;
+        }
+
+        self.classes[self.num_classes] = name;
+        self.num_classes += 1;
+
+        Some(self.num_classes as u16 - 1)
+    }
+
+    fn has_order(&self, from: u16, to: u16) -> bool {
+        self.deps[from as usize][to as usize / 64] & (1 << (to % 64)) != 0
+    }
+
+    fn add_order(&mut self, from: u16, to: u16, stack: &[usize]) {
+        self.deps[from as usize][to as usize / 64] |= 1 << (to % 64);
+
+        // Order is still checked if we've run out of room for it's stack
+        if self.num_orders < MAX_LOCK_ORDERS {
+            let order = &mut self.orders[self.num_orders];
+            order.from = from;
+            order.to = to;
+            order.stack[..stack.len()].copy_from_slice(stack);
+            order.depth = stack.len();
+
+            self.num_orders += 1;
+        }
+    }
+
+    fn get_order(&self, from: u16, to: u16) -> Option<&LockOrder> {
+        self.orders[..self.num_orders].iter().find(|order| order.from == from && order.to == to)
+    }
+
+    // Breadth first search for a chain of orders going from -> to. Returns the classes along it, from first
+    fn find_path(&self, from: u16, to: u16, path: &mut [u16; MAX_LOCK_CLASSES]) -> Option<usize> {
+        let mut parent = [NO_CLASS; MAX_LOCK_CLASSES];
+        let mut queue = [NO_CLASS; MAX_LOCK_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        parent[from as usize] = from;
+
+        while head < tail {
+            let class = queue[head];
+            head += 1;
+
+            if class == to {
+                // Walk back up to from, then flip it around
+                let mut len = 0;
+                let mut cur = to;
+                while cur != from {
+                    path[len] = cur;
+                    len += 1;
+                    cur = parent[cur as usize];
+                }
+
+                path[len] = from;
+                path[..len + 1].reverse();
+
+                return Some(len + 1);
+            }
+
+            for next in 1..self.num_classes as u16 {
+                if parent[next as usize] == NO_CLASS && self.has_order(class, next) {
+                    parent[next as usize] = class;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+// Starts validating. Called once every core is up
+pub fn init() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+// Declares the order in which these classes have to be taken, so that breaking it is caught on first sight
+// instead of only once both orders have been seen
+pub fn declare_lock_order(classes: &[&'static str]) {
+    with_graph(|graph| {
+        for pair in classes.windows(2) {
+            if let (Some(from), Some(to)) = (graph.get_class(pair[0]), graph.get_class(pair[1])) && !graph.has_order(from, to) {
+                graph.add_order(from, to, &[]);
+            }
+        }
+    });
+}
+
+fn with_graph<R>(f: impl FnOnce(&mut LockGraph) -> R) -> R {
+    let int_status = hal::disable_interrupts();
+    GRAPH.lock.lock();
+    let res = f(unsafe { &mut *GRAPH.graph.get() });
+    GRAPH.lock.unlock();
+    hal::enable_interrupts(int_status);
+
+    res
+}
+
+fn get_held_locks() -> &'static mut HeldLocks {
+    unsafe { &mut *HELD_LOCKS.local().0.get() }
+}
+
+// Runs f unless validation is off or the validator is already running on this core
+// Interrupts are disabled by the caller
+fn validate(f: impl FnOnce(&mut HeldLocks)) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let held = get_held_locks();
+    if held.busy {
+        return;
+    }
+
+    held.busy = true;
+    f(held);
+    held.busy = false;
+}
+
+// Class ids are looked up once per lock and cached in it
+fn get_class(class: &AtomicU16, name: &'static str) -> Option<u16> {
+    let id = class.load(Ordering::Relaxed);
+    if id != NO_CLASS {
+        return Some(id);
+    }
+
+    let id = with_graph(|graph| graph.get_class(name));
+    match id {
+        Some(id) => class.store(id, Ordering::Relaxed),
+        None => stop_validation("Out of lock classes")
+    }
+
+    id
+}
+
+// Turns validation off for good. Returns false if someone else already did
+fn disable() -> bool {
+    ENABLED.swap(false, Ordering::AcqRel)
+}
+
+fn stop_validation(reason: &str) {
+    if disable() {
+        println!("Lock validator turned off: {}", reason);
+    }
+}
+
+fn capture_stack(stack: &mut [usize; LOCKDEP_STACK_DEPTH]) -> usize {
+    hal::unwind_stack(LOCKDEP_STACK_DEPTH, cpu::get_panic_base_unlocked(), stack.as_mut_slice()).0
+}
+
+// Called by Spinlock and RwSpinlock right before they start spinning
+pub fn lock_acquiring(class: &AtomicU16, name: &'static str) {
+    validate(|held| {
+        let Some(class) = get_class(class, name) else {
+            return;
+        };
+
+        let mut stack = [0; LOCKDEP_STACK_DEPTH];
+        let mut depth = None;
+
+        with_graph(|graph| {
+            for &held_class in &held.classes[..held.depth] {
+                if held_class == class || graph.has_order(held_class, class) {
+                    continue;
+                }
+
+                // New order. It's fine as long as class isn't taken before held_class somewhere already
+                let mut path = [NO_CLASS; MAX_LOCK_CLASSES];
+                if let Some(len) = graph.find_path(class, held_class, &mut path) {
+                    let depth = depth.unwrap_or_else(|| capture_stack(&mut stack));
+                    if disable() {
+                        report_inversion(graph, held_class, class, &path[..len], &stack[..depth]);
+                    }
+
+                    break;
+                }
+
+                let depth = *depth.get_or_insert_with(|| capture_stack(&mut stack));
+                graph.add_order(held_class, class, &stack[..depth]);
+            }
+        });
+    });
+}
+
+// Called by Spinlock and RwSpinlock once the lock is held
+pub fn lock_acquired(class: &AtomicU16, name: &'static str) {
+    validate(|held| {
+        let Some(class) = get_class(class, name) else {
+            return;
+        };
+
+        if held.depth == MAX_HELD_LOCKS {
+            stop_validation("Too many locks held at once");
+            return;
+        }
+
+        held.classes[held.depth] = class;
+        held.depth += 1;
+    });
+}
+
+// Called by the lock guards right before the lock is let go
+// Locks don't have to be let go in the reverse order they were taken in
+pub fn lock_released(class: &AtomicU16) {
+    validate(|held| {
+        let class = class.load(Ordering::Relaxed);
+        if let Some(idx) = held.classes[..held.depth].iter().rposition(|&held_class| held_class == class) {
+            held.classes.copy_within(idx + 1..held.depth, idx);
+            held.depth -= 1;
+        }
+    });
+}
+
+// Called with the graph locked
+fn report_inversion(graph: &LockGraph, held_class: u16, class: u16, path: &[u16], stack: &[usize]) {
+    let name = |class: u16| graph.classes[class as usize];
+
+    println!("====Lock order inversion on core {}!!====", hal::get_core());
+    println!("Taking {} while holding {}", name(class), name(held_class));
+    println!("Callstack:");
+    infra::print_callstack(stack);
+
+    println!("But {} was already taken the other way round:", name(held_class));
+    for pair in path.windows(2) {
+        println!("{} -> {}", name(pair[0]), name(pair[1]));
+
+        match graph.get_order(pair[0], pair[1]) {
+            Some(order) if order.depth > 0 => {
+                println!("First seen at:");
+                infra::print_callstack(&order.stack[..order.depth]);
+            },
+            _ => {
+                println!("(Declared order)");
+            }
+        }
+    }
+}
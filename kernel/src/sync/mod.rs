@@ -1,7 +1,17 @@
+mod condvar;
 mod lock;
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+pub mod lockdep;
+mod mutex;
 mod once;
+mod pi_mutex;
+mod rwlock;
 mod semaphore;
 
+pub use condvar::*;
 pub use once::*;
 pub use lock::*;
+pub use mutex::*;
+pub use pi_mutex::*;
+pub use rwlock::*;
 pub use semaphore::*;
\ No newline at end of file
@@ -0,0 +1,105 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::{KSem, Once};
+use crate::sched;
+use kernel_intf::KError;
+
+const NO_OWNER: usize = usize::MAX;
+
+// Sleeping lock for long critical sections (Ones that do I/O or block on something else)
+// Waiters sleep on a binary KSem instead of spinning with interrupts off
+// Owner is recorded, so that recursive locking and unlocking by another task get caught in debug builds
+// Can't be used from interrupt context or the idle task
+pub struct KMutex<T> {
+    // Created on first use, so that new() can be const
+    sem: Once<KSem>,
+    // Id of the task holding the mutex
+    owner: AtomicUsize,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for KMutex<T> {}
+unsafe impl<T: Send> Send for KMutex<T> {}
+
+pub struct KMutexGuard<'a, T> {
+    mutex: &'a KMutex<T>,
+    // Has to be dropped by the task that took the lock
+    _not_send: PhantomData<*const ()>
+}
+
+impl<T> Deref for KMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe {&*self.mutex.data.get()}
+    }
+}
+
+impl<T> DerefMut for KMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe {&mut *self.mutex.data.get()}
+    }
+}
+
+impl<T> Drop for KMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            let task_id = sched::get_current_task_id();
+            let owner = self.mutex.owner.load(Ordering::Relaxed);
+            assert!(task_id == Some(owner), "KMutex owned by task {} unlocked by task {:?}!", owner, task_id);
+        }
+
+        self.mutex.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.mutex.get_sem().signal();
+    }
+}
+
+impl<'a, T> KMutexGuard<'a, T> {
+    pub fn get_mutex(&self) -> &'a KMutex<T> {
+        self.mutex
+    }
+}
+
+impl<T> KMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            sem: Once::new(),
+            owner: AtomicUsize::new(NO_OWNER),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    fn get_sem(&self) -> &KSem {
+        self.sem.call_once(|| KSem::new(1, 1));
+        self.sem.get().unwrap()
+    }
+
+    // Fails if the task got killed before it could go to sleep
+    pub fn lock(&self) -> Result<KMutexGuard<'_, T>, KError> {
+        let task_id = sched::get_current_task_id()
+        .expect("KMutex::lock() called from idle task!!");
+
+        #[cfg(debug_assertions)]
+        assert!(self.owner.load(Ordering::Relaxed) != task_id, "KMutex locked recursively by task {}!", task_id);
+
+        self.get_sem().wait()?;
+        self.owner.store(task_id, Ordering::Relaxed);
+
+        Ok(KMutexGuard { mutex: self, _not_send: PhantomData })
+    }
+
+    // Id of the task holding the mutex right now
+    pub fn get_owner(&self) -> Option<usize> {
+        let owner = self.owner.load(Ordering::Relaxed);
+        (owner != NO_OWNER).then_some(owner)
+    }
+
+    // This gives access to underlying data without locking
+    // Only used in infra code during exception handling
+    pub unsafe fn as_ref(&self) -> &T {
+        unsafe { &*self.data.get() }
+    }
+}
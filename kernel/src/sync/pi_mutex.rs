@@ -0,0 +1,283 @@
+use alloc::sync::Arc;
+use super::{KSem, Spinlock};
+use crate::{ds::*, mem::PoolAllocatorGlobal, sched::{self, KThread, TaskPriority, TaskStatus}};
+use kernel_intf::KError;
+
+pub type KPiMutexInnerType = Arc<Spinlock<KPiMutexInner>, PoolAllocatorGlobal>;
+
+// Serializes priority inheritance bookkeeping, so that a boost and an unboost of the same task can't cross each other
+// Lock order => PI_LOCK -> KPiMutex -> Scheduler -> Process -> Task
+static PI_LOCK: Spinlock<()> = Spinlock::new_with_class((), "PI_LOCK");
+
+struct Waiter {
+    task: KThread,
+    // Signalled once ownership has been handed over to this waiter
+    wake: KSem
+}
+
+pub struct KPiMutexInner {
+    owner: Option<KThread>,
+    waiters: DynList<Waiter>
+}
+
+impl KPiMutexInner {
+    // Highest priority among the waiters, which is what the owner inherits
+    fn get_top_priority(&self) -> Option<TaskPriority> {
+        self.waiters.iter().map(|waiter| waiter.task.get_priority())
+        .reduce(|top, priority| if priority.is_higher_than(top) { priority } else { top })
+    }
+
+    fn is_owner(&self, task: &KThread) -> bool {
+        self.owner.as_ref().is_some_and(|owner| Arc::ptr_eq(owner, task))
+    }
+
+    fn set_owner(&mut self, this: &KPiMutexInnerType, task: &KThread) {
+        {
+            let mut task = task.lock();
+            task.set_pi_blocked_on(None);
+            task.add_pi_mutex(Arc::clone(this));
+        }
+
+        self.owner = Some(Arc::clone(task));
+    }
+}
+
+// Sleeping mutex that tracks it's owner
+// While someone waits on it, the owner runs at the waiter's priority if that's higher than it's own. The boost follows
+// the chain of owners in case the owner is itself waiting on another KPiMutex, and is undone once the mutex is released
+// Ownership is handed straight to the highest priority waiter on unlock
+// Do not use it from interrupt context
+pub struct KPiMutex {
+    inner: KPiMutexInnerType
+}
+
+unsafe impl Sync for KPiMutex {}
+unsafe impl Send for KPiMutex {}
+
+pub struct KPiMutexGuard<'a> {
+    mutex: &'a KPiMutex
+}
+
+impl Drop for KPiMutexGuard<'_> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl Default for KPiMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KPiMutex {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new_in(Spinlock::new(KPiMutexInner {
+                owner: None,
+                waiters: List::new()
+            }), PoolAllocatorGlobal)
+        }
+    }
+
+    // Fails with KError::CircularDependency if the current task already owns the mutex
+    pub fn lock(&self) -> Result<KPiMutexGuard<'_>, KError> {
+        let cur_task = sched::get_current_task()
+        .expect("KPiMutex::lock() called from idle task!!");
+
+        if self.try_acquire(&cur_task)? {
+            return Ok(KPiMutexGuard { mutex: self });
+        }
+
+        let wake = KSem::new(0, 1);
+        {
+            let _pi_guard = PI_LOCK.lock();
+            {
+                let mut inner = self.inner.lock();
+                if inner.owner.is_none() {
+                    cur_task.lock().reserve_pi_mutex()?;
+                    inner.set_owner(&self.inner, &cur_task);
+
+                    return Ok(KPiMutexGuard { mutex: self });
+                }
+                else if inner.is_owner(&cur_task) {
+                    return Err(KError::CircularDependency);
+                }
+
+                // Ownership is handed over while this task is asleep, so make room for it now
+                cur_task.lock().reserve_pi_mutex()?;
+                if let Err(e) = inner.waiters.add_node(Waiter {
+                    task: Arc::clone(&cur_task),
+                    wake: wake.clone()
+                }) {
+                    cur_task.lock().release_pi_mutex();
+                    return Err(e);
+                }
+
+                cur_task.lock().set_pi_blocked_on(Some(Arc::clone(&self.inner)));
+            }
+
+            propagate_priority(Arc::clone(&self.inner), cur_task.get_priority());
+        }
+
+        if let Err(e) = wake.wait() {
+            // Task got killed before it could block. It could still have been handed the mutex in the meantime
+            let _pi_guard = PI_LOCK.lock();
+            let owner = {
+                let mut inner = self.inner.lock();
+                if inner.is_owner(&cur_task) {
+                    return Ok(KPiMutexGuard { mutex: self });
+                }
+
+                inner.waiters.find_and_remove(|waiter| Arc::ptr_eq(&waiter.task, &cur_task));
+                let mut task = cur_task.lock();
+                task.set_pi_blocked_on(None);
+                task.release_pi_mutex();
+                inner.owner.clone()
+            };
+
+            // Owner could be running at our priority, and so could whoever it's waiting on
+            if let Some(owner) = owner {
+                unwind_priority(owner);
+            }
+
+            return Err(e);
+        }
+
+        Ok(KPiMutexGuard { mutex: self })
+    }
+
+    fn try_acquire(&self, cur_task: &KThread) -> Result<bool, KError> {
+        let mut inner = self.inner.lock();
+        if inner.owner.is_some() {
+            return Ok(false);
+        }
+
+        cur_task.lock().reserve_pi_mutex()?;
+        inner.set_owner(&self.inner, cur_task);
+
+        Ok(true)
+    }
+
+    fn unlock(&self) {
+        let cur_task = sched::get_current_task()
+        .expect("KPiMutex unlocked from idle task!!");
+
+        {
+            let mut inner = self.inner.lock();
+            if !inner.is_owner(&cur_task) {
+                let task_id = cur_task.lock().get_id();
+                panic!("KPiMutex unlocked by task {} which doesn't own it!", task_id);
+            }
+
+            // Nobody is waiting, so nobody could have lent us their priority through this mutex
+            if inner.waiters.get_nodes() == 0 {
+                cur_task.lock().remove_pi_mutex(&self.inner);
+                inner.owner = None;
+                return;
+            }
+        }
+
+        let _pi_guard = PI_LOCK.lock();
+        let next = {
+            let mut inner = self.inner.lock();
+            cur_task.lock().remove_pi_mutex(&self.inner);
+
+            // Waiters that got killed while blocked never come back for it
+            while inner.waiters.find_and_remove(|waiter| waiter.task.lock().get_status() == TaskStatus::TERMINATED).is_some() {}
+
+            // First one wins among equals, so that waiters of the same priority go in order
+            let top = inner.waiters.iter().reduce(|top, waiter| {
+                if waiter.task.get_priority().is_higher_than(top.task.get_priority()) { waiter } else { top }
+            }).map(|waiter| Arc::clone(&waiter.task));
+
+            let next = top.and_then(|top| inner.waiters.find_and_remove(|waiter| Arc::ptr_eq(&waiter.task, &top)));
+            match next.as_ref() {
+                Some(next) => inner.set_owner(&self.inner, &next.task),
+                None => inner.owner = None
+            }
+
+            next
+        };
+
+        // Give back whatever was inherited through this mutex
+        update_inherited_priority(&cur_task);
+
+        if let Some(next) = next {
+            // New owner inherits from the ones still waiting
+            update_inherited_priority(&next.task);
+            next.wake.signal();
+        }
+    }
+}
+
+// Deadline class needs a reservation, so Deadline waiters lend RealTime instead
+fn get_inheritable(priority: TaskPriority) -> TaskPriority {
+    if priority == TaskPriority::Deadline {
+        TaskPriority::RealTime
+    }
+    else {
+        priority
+    }
+}
+
+// Walks the chain of owners starting at mutex, raising each one to priority
+// Stops once an owner already runs at priority or higher, which is also what ends the walk on a deadlock cycle
+// Called with PI_LOCK held
+fn propagate_priority(mut mutex: KPiMutexInnerType, priority: TaskPriority) {
+    let priority = get_inheritable(priority);
+
+    loop {
+        let next = {
+            let inner = mutex.lock();
+            let Some(owner) = inner.owner.as_ref() else {
+                return;
+            };
+
+            if !priority.is_higher_than(owner.get_priority()) {
+                return;
+            }
+
+            owner.set_inherited_priority(priority);
+            owner.lock().get_pi_blocked_on()
+        };
+
+        match next {
+            Some(next) => mutex = next,
+            None => return
+        }
+    }
+}
+
+// Walks the chain of owners starting at task, recomputing what each one inherits after a waiter went away
+// Stops once an owner's priority stays the same, since nobody further along the chain inherited from it then
+// Called with PI_LOCK held
+fn unwind_priority(mut task: KThread) {
+    loop {
+        let old_priority = task.get_priority();
+        update_inherited_priority(&task);
+        if task.get_priority() == old_priority {
+            return;
+        }
+
+        let Some(mutex) = task.lock().get_pi_blocked_on() else {
+            return;
+        };
+
+        let Some(owner) = mutex.lock().owner.clone() else {
+            return;
+        };
+
+        task = owner;
+    }
+}
+
+// Recomputes what the task inherits from the mutexes it holds
+// Called with PI_LOCK held
+fn update_inherited_priority(task: &KThread) {
+    let held = task.lock().get_pi_mutexes();
+    let priority = held.iter().filter_map(|mutex| mutex.lock().get_top_priority())
+    .fold(TaskPriority::Idle, |top, priority| if priority.is_higher_than(top) { priority } else { top });
+
+    task.set_inherited_priority(get_inheritable(priority));
+}
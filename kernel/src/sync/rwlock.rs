@@ -0,0 +1,370 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::{KSem, Once, Spinlock};
+use crate::hal;
+use kernel_intf::KError;
+
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+use core::sync::atomic::AtomicU16;
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+use super::lockdep;
+
+// Top bit of the state is set while a writer holds the lock. The rest counts the readers
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+// Spinning reader-writer lock. Interrupts are disabled while it's held, same as Spinlock
+// Writers go first: Once a writer is waiting, new readers hold off, so that a steady stream of readers can't starve it
+// This also means read locks don't nest. Taking the read lock again while already holding it (Directly, from a callback
+// or from an exception taken with it held) deadlocks as soon as a writer shows up on another core: The inner read waits
+// on the writer, which waits on the outer read. So never call out to code that could come back to the same lock
+pub struct RwSpinlock<T> {
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    // Lock validator class, same as Spinlock. Readers and writers are checked alike
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    class: AtomicU16,
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    class_name: Option<&'static str>,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for RwSpinlock<T> {}
+
+pub struct RwSpinlockReadGuard<'a, T> {
+    lock: &'a RwSpinlock<T>,
+    int_status: bool
+}
+
+pub struct RwSpinlockWriteGuard<'a, T> {
+    lock: &'a RwSpinlock<T>,
+    int_status: bool
+}
+
+impl<T> Deref for RwSpinlockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe {&*self.lock.data.get()}
+    }
+}
+
+impl<T> Drop for RwSpinlockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+        lockdep::lock_released(&self.lock.class);
+
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        hal::enable_interrupts(self.int_status);
+    }
+}
+
+impl<T> Deref for RwSpinlockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe {&*self.lock.data.get()}
+    }
+}
+
+impl<T> DerefMut for RwSpinlockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe {&mut *self.lock.data.get()}
+    }
+}
+
+impl<T> Drop for RwSpinlockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+        lockdep::lock_released(&self.lock.class);
+
+        self.lock.state.store(0, Ordering::Release);
+        hal::enable_interrupts(self.int_status);
+    }
+}
+
+impl<T> RwSpinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self::create(data, None)
+    }
+
+    // See Spinlock::new_with_class
+    pub const fn new_with_class(data: T, class: &'static str) -> Self {
+        Self::create(data, Some(class))
+    }
+
+    #[allow(unused_variables)]
+    const fn create(data: T, class: Option<&'static str>) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+            class: AtomicU16::new(lockdep::NO_CLASS),
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+            class_name: class,
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    // Named after the lock type as well, so that it doesn't share a class with a Spinlock guarding the same type
+#[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+    fn get_class_name(&self) -> &'static str {
+        self.class_name.unwrap_or(core::any::type_name::<Self>())
+    }
+
+    pub fn read(&self) -> RwSpinlockReadGuard<'_, T> {
+        let int_status = hal::disable_interrupts();
+
+        #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+        lockdep::lock_acquiring(&self.class, self.get_class_name());
+
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0 && self.waiting_writers.load(Ordering::Relaxed) == 0 &&
+            self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                break;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+        lockdep::lock_acquired(&self.class, self.get_class_name());
+
+        RwSpinlockReadGuard { lock: self, int_status }
+    }
+
+    pub fn write(&self) -> RwSpinlockWriteGuard<'_, T> {
+        let int_status = hal::disable_interrupts();
+
+        #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+        lockdep::lock_acquiring(&self.class, self.get_class_name());
+
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        while self.state.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+
+        #[cfg(all(debug_assertions, feature = "deadlock_detection", not(test)))]
+        lockdep::lock_acquired(&self.class, self.get_class_name());
+
+        RwSpinlockWriteGuard { lock: self, int_status }
+    }
+
+    // This gives access to underlying data without locking
+    // Only used in infra code during exception handling
+    pub unsafe fn as_ref(&self) -> &T {
+        unsafe { &*self.data.get() }
+    }
+}
+
+struct KRwState {
+    readers: usize,
+    writer: bool,
+    // Tasks that are (about to go) asleep on the semaphores. Every waiter takes itself off once it wakes up
+    waiting_readers: usize,
+    waiting_writers: usize
+}
+
+impl KRwState {
+    // Who should be woken up now. Writers go first
+    fn get_wakeups(&self) -> (usize, usize) {
+        if self.writer || self.readers > 0 {
+            (0, 0)
+        }
+        else if self.waiting_writers > 0 {
+            (0, 1)
+        }
+        else {
+            (self.waiting_readers, 0)
+        }
+    }
+}
+
+struct KRwSems {
+    read: KSem,
+    write: KSem
+}
+
+// Sleeping reader-writer lock, for read-mostly data that's held across blocking calls
+// Writers go first, same as RwSpinlock. Woken up waiters check the lock again instead of being handed it, so a stray
+// wake up (Say from a waiter that got killed) only costs a trip around the loop
+// Can't be used from interrupt context or the idle task
+pub struct KRwLock<T> {
+    state: Spinlock<KRwState>,
+    // Created on first use, so that new() can be const
+    sems: Once<KRwSems>,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for KRwLock<T> {}
+unsafe impl<T: Send> Send for KRwLock<T> {}
+
+pub struct KRwLockReadGuard<'a, T> {
+    lock: &'a KRwLock<T>,
+    // Has to be dropped by the task that took the lock
+    _not_send: PhantomData<*const ()>
+}
+
+pub struct KRwLockWriteGuard<'a, T> {
+    lock: &'a KRwLock<T>,
+    _not_send: PhantomData<*const ()>
+}
+
+impl<T> Deref for KRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe {&*self.lock.data.get()}
+    }
+}
+
+impl<T> Drop for KRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let wakeups = {
+            let mut state = self.lock.state.lock();
+            state.readers -= 1;
+            state.get_wakeups()
+        };
+
+        self.lock.wake(wakeups);
+    }
+}
+
+impl<T> Deref for KRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe {&*self.lock.data.get()}
+    }
+}
+
+impl<T> DerefMut for KRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe {&mut *self.lock.data.get()}
+    }
+}
+
+impl<T> Drop for KRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let wakeups = {
+            let mut state = self.lock.state.lock();
+            state.writer = false;
+            state.get_wakeups()
+        };
+
+        self.lock.wake(wakeups);
+    }
+}
+
+impl<T> KRwLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: Spinlock::new(KRwState {
+                readers: 0,
+                writer: false,
+                waiting_readers: 0,
+                waiting_writers: 0
+            }),
+            sems: Once::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    fn get_sems(&self) -> &KRwSems {
+        self.sems.call_once(|| KRwSems {
+            read: KSem::new(0, isize::MAX),
+            write: KSem::new(0, isize::MAX)
+        });
+
+        self.sems.get().unwrap()
+    }
+
+    fn wake(&self, (readers, writers): (usize, usize)) {
+        let sems = self.get_sems();
+
+        for _ in 0..readers {
+            sems.read.signal();
+        }
+
+        for _ in 0..writers {
+            sems.write.signal();
+        }
+    }
+
+    // Fails if the task got killed before it could go to sleep
+    pub fn read(&self) -> Result<KRwLockReadGuard<'_, T>, KError> {
+        let mut waiting = false;
+
+        loop {
+            {
+                let mut state = self.state.lock();
+                if waiting {
+                    state.waiting_readers -= 1;
+                }
+
+                if !state.writer && state.waiting_writers == 0 {
+                    state.readers += 1;
+                    return Ok(KRwLockReadGuard { lock: self, _not_send: PhantomData });
+                }
+
+                state.waiting_readers += 1;
+                waiting = true;
+            }
+
+            if let Err(e) = self.get_sems().read.wait() {
+                let wakeups = {
+                    let mut state = self.state.lock();
+                    state.waiting_readers -= 1;
+                    state.get_wakeups()
+                };
+
+                // We might have eaten a wake up meant for someone else
+                self.wake(wakeups);
+                return Err(e);
+            }
+        }
+    }
+
+    // Fails if the task got killed before it could go to sleep
+    pub fn write(&self) -> Result<KRwLockWriteGuard<'_, T>, KError> {
+        let mut waiting = false;
+
+        loop {
+            {
+                let mut state = self.state.lock();
+                if waiting {
+                    state.waiting_writers -= 1;
+                }
+
+                if !state.writer && state.readers == 0 {
+                    state.writer = true;
+                    return Ok(KRwLockWriteGuard { lock: self, _not_send: PhantomData });
+                }
+
+                state.waiting_writers += 1;
+                waiting = true;
+            }
+
+            if let Err(e) = self.get_sems().write.wait() {
+                let wakeups = {
+                    let mut state = self.state.lock();
+                    state.waiting_writers -= 1;
+                    state.get_wakeups()
+                };
+
+                // Readers held off for us can go ahead now
+                self.wake(wakeups);
+                return Err(e);
+            }
+        }
+    }
+
+    // This gives access to underlying data without locking
+    // Only used in infra code during exception handling
+    pub unsafe fn as_ref(&self) -> &T {
+        unsafe { &*self.data.get() }
+    }
+}
@@ -1,7 +1,8 @@
 use core::ptr::NonNull;
 use alloc::sync::Arc;
-use super::Spinlock;
-use crate::{ds::*, mem::PoolAllocatorGlobal, sched::{self, KTimerInnerType}};
+use alloc::vec::Vec;
+use super::{Spinlock, SpinlockGuard};
+use crate::{ds::*, hal, mem::PoolAllocatorGlobal, sched::{self, KTimer, KTimerInnerType}};
 use kernel_intf::KError;
 use crate::sched::KThread;
 
@@ -131,7 +132,9 @@ impl KSem {
         }
     }
 
-    pub fn drop_task(inner_arc: KSemInnerType, task_id: usize) {
+    // Takes the task off the blocked list and gives back the count it was waiting for
+    // Returns false if the task wasn't on the list
+    pub fn drop_task(inner_arc: KSemInnerType, task_id: usize) -> bool {
         let mut inner = inner_arc.lock();
 
         let mut blocked_task = None;
@@ -149,8 +152,158 @@ impl KSem {
             unsafe {
                 inner.blocked_list.remove_node(blocked_task.unwrap());
             }
+
+            inner.counter += 1;
+            return true;
+        }
+
+        false
+    }
+}
+
+// Semaphores locked together by wait_any(). Let go in the reverse order, so that interrupts stay off till the last one
+struct LockedSems<'a>(Vec<(usize, SpinlockGuard<'a, KSemInner>)>);
+
+impl Drop for LockedSems<'_> {
+    fn drop(&mut self) {
+        while self.0.pop().is_some() {}
+    }
+}
+
+impl LockedSems<'_> {
+    // Gives back what was taken for the first count of them
+    fn undo_wait(&mut self, count: usize) {
+        for (_, inner) in self.0.iter_mut().take(count) {
+            inner.counter += 1;
+            inner.blocked_list.pop_node();
+        }
+    }
+}
+
+// Waits till any one of sems can be taken and returns it's index. Only that one is taken
+// If more than one is free, the one earliest in sems wins
+// Fails with KError::Timeout if none could be taken within timeout_ms. A timeout of 0 doesn't block at all
+pub fn wait_any(sems: &[&KSem], timeout_ms: Option<usize>) -> Result<usize, KError> {
+    if sems.is_empty() {
+        return Err(KError::InvalidArgument);
+    }
+
+    let cur_task = sched::get_current_task()
+    .expect("wait_any() called from idle task!!");
+    let task_id = cur_task.lock().get_id();
+
+    // Timer reports expiry on a semaphore of it's own, which is waited on along with the rest
+    let timer_sem = KSem::new(0, 1);
+    let timer = timeout_ms.map(|timeout_ms| KTimer::new_with_semaphore(timeout_ms, timer_sem.clone()));
+
+    let mut sources: Vec<KSemInnerType> = sems.iter().map(|sem| Arc::clone(&sem.inner)).collect();
+    if timer.is_some() {
+        sources.push(Arc::clone(&timer_sem.inner));
+    }
+
+    // Everything is locked in address order, so that two tasks waiting on the same semaphores can't deadlock
+    let mut order: Vec<usize> = (0..sources.len()).collect();
+    order.sort_unstable_by_key(|&idx| Arc::as_ptr(&sources[idx]));
+    if order.windows(2).any(|pair| Arc::ptr_eq(&sources[pair[0]], &sources[pair[1]])) {
+        return Err(KError::InvalidArgument);
+    }
+
+    {
+        // All of them stay locked till we're on the wait queue, so that a signal can't slip in between
+        let mut locked = LockedSems(order.iter().map(|&idx| (idx, sources[idx].lock())).collect());
+
+        let free = locked.0.iter_mut().filter(|(idx, inner)| *idx < sems.len() && inner.counter > 0)
+        .min_by_key(|(idx, _)| *idx);
+        if let Some((idx, inner)) = free {
+            inner.counter -= 1;
+            return Ok(*idx);
+        }
+
+        if timeout_ms == Some(0) {
+            return Err(KError::Timeout);
+        }
+
+        // Block on every one of them. Whichever signals first hands it's count to us
+        for count in 0..locked.0.len() {
+            let inner = &mut locked.0[count].1;
+            inner.counter -= 1;
+            if let Err(e) = inner.blocked_list.add_node(Arc::clone(&cur_task)) {
+                inner.counter += 1;
+                locked.undo_wait(count);
+
+                return Err(e);
+            }
+        }
+
+        if !sched::add_cur_task_to_wait_queue_multi(&sources, timer.as_ref().map(KTimer::get_inner)) {
+            locked.undo_wait(sources.len());
+
+            return Err(KError::WaitFailed);
         }
     }
+
+    sched::yield_cpu();
+
+    // Woken up by one of the others, so the timer would only keep it's core ticking for nothing
+    if let Some(timer) = &timer {
+        timer.cancel();
+    }
+
+    let woken_by = if sources.len() == 1 {
+        Arc::clone(&sources[0])
+    }
+    else {
+        cur_task.lock().take_woken_by().expect("wait_any() woken up without a source!")
+    };
+
+    // Cancel the wait on the rest
+    let mut woken_idx = 0;
+    for (idx, source) in sources.iter().enumerate() {
+        if Arc::ptr_eq(source, &woken_by) {
+            woken_idx = idx;
+        }
+        else if !KSem::drop_task(Arc::clone(source), task_id) {
+            // This one signalled us as well. We only take one, so pass it on
+            KSem { inner: Arc::clone(source) }.signal();
+        }
+    }
+
+    if woken_idx == sems.len() {
+        Err(KError::Timeout)
+    }
+    else {
+        Ok(woken_idx)
+    }
+}
+
+// Waits till every one of sems has been taken
+// They're taken one at a time as they come free, not all at once. On failure (Including KError::Timeout) the ones
+// taken so far are given back
+pub fn wait_all(sems: &[&KSem], timeout_ms: Option<usize>) -> Result<(), KError> {
+    if sems.is_empty() {
+        return Err(KError::InvalidArgument);
+    }
+
+    let deadline = timeout_ms.map(|timeout_ms| hal::get_time_ms() + timeout_ms);
+    let mut pending: Vec<&KSem> = sems.to_vec();
+    let mut taken: Vec<&KSem> = Vec::new();
+
+    while !pending.is_empty() {
+        let timeout_ms = deadline.map(|deadline| deadline.saturating_sub(hal::get_time_ms()));
+
+        match wait_any(&pending, timeout_ms) {
+            Ok(idx) => taken.push(pending.remove(idx)),
+            Err(e) => {
+                for sem in taken {
+                    sem.signal();
+                }
+
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
 }
 
 impl Clone for KSem {
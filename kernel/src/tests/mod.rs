@@ -1,7 +1,7 @@
 use core::{alloc::Layout, ptr::NonNull};
 use std::{sync::{Arc, Mutex, OnceLock}};
 
-use crate::{ds::*, mem};
+use crate::{ds::*, hal, mem};
 use kernel_intf::KError;
 use common::test_log;
 
@@ -293,4 +293,58 @@ fn virt_alloc_test() {
     mem::setup_heap();
     test_log!("Starting virt_alloc_test");
     mem::virtual_allocator_test();
-}
\ No newline at end of file
+}
+
+#[test]
+fn id_alloc_test() {
+    test_log!("Starting id_alloc_test");
+    let mut ids = IdAllocator::new(100, 2);
+
+    for expected in 0..70 {
+        assert_eq!(ids.alloc().unwrap(), expected);
+    }
+
+    // Freed ids only come back once two more ids have been freed after them
+    assert!(ids.free(3));
+    assert!(ids.free(65));
+    assert_eq!(ids.alloc().unwrap(), 70);
+
+    // An id waiting out it's grace period is no longer allocated, so freeing it again is caught
+    assert!(!ids.is_allocated(3));
+    assert!(!ids.free(3));
+    assert!(!ids.free(99));
+
+    assert!(ids.free(70));
+    assert_eq!(ids.alloc().unwrap(), 3);
+    assert!(ids.free(0));
+    assert_eq!(ids.alloc().unwrap(), 65);
+
+    // With the id space used up, the oldest freed id is reused early
+    while ids.alloc().unwrap() < 99 {}
+    assert_eq!(ids.alloc().unwrap(), 70);
+    assert_eq!(ids.alloc().unwrap(), 0);
+    assert!(ids.alloc().is_err_and(|e| e == KError::LimitExceeded));
+
+    ids.set_max_ids(101);
+    assert_eq!(ids.alloc().unwrap(), 100);
+    assert!(ids.is_allocated(100));
+}
+
+#[test]
+fn user_context_sanitize_test() {
+    test_log!("Starting user_context_sanitize_test");
+    let mut context = hal::UserContext::default();
+
+    context.set_entry(0x40_0000, 0, 0, 0x7FFF_FFFF_E000);
+    assert!(context.sanitize());
+
+    // First non canonical address. iretq to it would fault in ring 0
+    context.set_entry(1 << 47, 0, 0, 0x7FFF_FFFF_E000);
+    assert!(!context.sanitize());
+
+    context.set_entry(0x40_0000, 0, 0, 1 << 47);
+    assert!(!context.sanitize());
+
+    context.set_entry(hal::USER_SPACE_END, 0, 0, 0x7FFF_FFFF_E000);
+    assert!(!context.sanitize());
+}
@@ -10,6 +10,9 @@ pub mod madt;
 #[cfg(target_arch="x86_64")]
 pub const PAGE_SIZE: usize = 4096;
 pub const MAX_DESCRIPTORS: usize = 200;
+pub const MAX_CMDLINE: usize = 256;
+// Path of the kernel image in the init fs
+pub const KERNEL_FILE: &str = "/sys/aris";
 
 pub struct FileDescriptor<'a> {
     pub contents: &'a[u8],
@@ -89,10 +92,19 @@ pub struct BootInfo {
     pub framebuffer_desc: FBInfo,
     pub memory_map_desc: ArrayTable,
     pub init_fs: ArrayTable,
+    // Kernel command line, which is whatever the bootloader was started with. Only ASCII, not null terminated
+    pub cmdline: [u8; MAX_CMDLINE],
+    pub cmdline_len: usize,
 #[cfg(feature = "acpi")]
     pub rsdp: usize
 }
 
+impl BootInfo {
+    pub fn get_cmdline(&self) -> &str {
+        core::str::from_utf8(&self.cmdline[..self.cmdline_len]).unwrap_or("")
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct PixelMask {
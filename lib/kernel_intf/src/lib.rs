@@ -13,13 +13,19 @@ pub enum KError {
     OutOfMemory,
     ProcessTerminated,
     WaitFailed,
-    CircularDependency
+    CircularDependency,
+    Killed,
+    LimitExceeded,
+    Timeout
 }
 
 pub const E_SUCCESS: i64 = 0;
 pub const E_INVALID: i64 = -1;
 pub const E_OOM: i64 = -2;
 pub const E_INTERNAL_FAILURE: i64 = -3;
+pub const E_KILLED: i64 = -4;
+pub const E_LIMIT: i64 = -5;
+pub const E_TIMEOUT: i64 = -6;
 
 impl<T> From<Result<T, KError>> for KError {
     fn from(e: Result<T, KError>) -> Self {
@@ -33,7 +39,10 @@ impl From<KError> for i64 {
             KError::Success => E_SUCCESS,
             KError::InvalidArgument => E_INVALID,
             KError::OutOfMemory => E_OOM,
-            KError::ProcessTerminated | KError::WaitFailed | KError::CircularDependency => E_INTERNAL_FAILURE
+            KError::ProcessTerminated | KError::WaitFailed | KError::CircularDependency => E_INTERNAL_FAILURE,
+            KError::Killed => E_KILLED,
+            KError::LimitExceeded => E_LIMIT,
+            KError::Timeout => E_TIMEOUT
         }
     }
 }
@@ -47,6 +56,9 @@ impl fmt::Display for KError {
             KError::ProcessTerminated => "Process terminated",
             KError::WaitFailed => "Wait internal failure",
             KError::CircularDependency => "Circular dependency in module load",
+            KError::Killed => "Killed",
+            KError::LimitExceeded => "Resource limit exceeded",
+            KError::Timeout => "Timed out",
             KError::Success => "Success"
         };
         write!(f, "{}", description)